@@ -1,8 +1,16 @@
-use crate::balancing::LoadBalancing;
+use crate::balancing::{self, LoadBalancing, RequestContext};
+use crate::health::{Health, HealthCheck, HealthState, Outlier, OutlierDetection};
+use log::warn;
+use serde::Deserialize;
 use std::error::Error;
 use std::fmt;
-use std::ops::{Index, IndexMut};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::ops::Index;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{self, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 #[derive(Debug, PartialEq)]
 pub enum BackendError {
@@ -21,12 +29,249 @@ impl Error for BackendError {
     }
 }
 
+/// Limits of the pool of upstream connections kept by every `Backend`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ConnectionPoolConfig {
+    /// Max number of idle connections kept open waiting to be reused
+    pub max_idle: usize,
+    /// Time in milliseconds an idle connection is kept open before being closed
+    pub idle_timeout: u64,
+    /// Time in milliseconds since its creation after which a connection is not reused anymore
+    pub max_lifetime: u64,
+    /// Max number of connections in use towards the backend at the same time, further requests
+    /// wait for one of them to be released
+    pub max_connections: usize,
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> ConnectionPoolConfig {
+        ConnectionPoolConfig {
+            max_idle: 32,
+            idle_timeout: 30000,
+            max_lifetime: 600000,
+            max_connections: 1024,
+        }
+    }
+}
+
+/// Time constant of the moving averages of the backend latencies, a sample weights half as much
+/// after about 7 seconds.
+const LATENCY_DECAY: Duration = Duration::from_secs(10);
+
+/// Peak-sensitive exponentially weighted moving average of latency samples, in milliseconds.
+///
+/// The weight of the previous average decays with the time elapsed since the last sample,
+/// rather than with the number of samples, so that a backend receiving few requests doesn't
+/// keep a stale average. Samples higher than the average replace it right away, so a backend
+/// slowing down is detected at the first slow response, while it recovers gradually.
+#[derive(Debug)]
+pub struct Ewma {
+    state: Mutex<Option<(f64, Instant)>>,
+}
+
+impl Ewma {
+    pub fn new() -> Ewma {
+        Ewma {
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Add a sample to the average
+    pub fn observe(&self, sample: Duration) {
+        let sample = sample.as_secs_f64() * 1000.0;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let value = match *state {
+            Some((value, _)) if sample > value => sample,
+            Some((value, stamp)) => {
+                let elapsed = now.duration_since(stamp).as_secs_f64();
+                let weight = (-elapsed / LATENCY_DECAY.as_secs_f64()).exp();
+                value * weight + sample * (1.0 - weight)
+            }
+            None => sample,
+        };
+        *state = Some((value, now));
+    }
+
+    /// Return the current average in milliseconds, 0 if no sample has been observed yet
+    pub fn value(&self) -> f64 {
+        self.state.lock().unwrap().map_or(0.0, |(value, _)| value)
+    }
+}
+
+impl Default for Ewma {
+    fn default() -> Self {
+        Ewma::new()
+    }
+}
+
+/// Guard of a request in flight to a `Backend`, returned by `Backend::start_request`. Dropping it
+/// marks the request as completed, whatever the outcome.
+#[derive(Debug)]
+pub struct InFlightRequest<'a> {
+    backend: &'a Backend,
+}
+
+impl Drop for InFlightRequest<'_> {
+    fn drop(&mut self) {
+        self.backend.in_flight.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Configuration of a single backend. In the configuration file it's either the plain address of
+/// the backend or a map with the address and the optional settings, e.g.
+/// `{ addr: "127.0.0.1:7892", weight: 3 }`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "BackendEntry")]
+pub struct BackendConfig {
+    pub addr: String,
+    /// Relative capacity of the backend used by the weighted balancing algorithms, a weight of
+    /// 0 drains the backend, which doesn't get new requests
+    pub weight: u32,
+    /// Health check of the backend, replacing the default one of the pool
+    pub health_check: Option<HealthCheck>,
+    /// Start the backend draining: it's still checked but gets no requests
+    pub drain: bool,
+}
+
+impl BackendConfig {
+    /// Create the configuration of a backend with the default settings
+    pub fn new(addr: String) -> BackendConfig {
+        BackendConfig {
+            addr,
+            weight: 1,
+            health_check: None,
+            drain: false,
+        }
+    }
+}
+
+/// The two forms a backend can be declared with in the configuration file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackendEntry {
+    Addr(String),
+    Full {
+        addr: String,
+        #[serde(default = "BackendEntry::default_weight")]
+        weight: u32,
+        #[serde(default)]
+        health_check: Option<HealthCheck>,
+        #[serde(default)]
+        drain: bool,
+    },
+}
+
+impl BackendEntry {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+impl From<BackendEntry> for BackendConfig {
+    fn from(entry: BackendEntry) -> BackendConfig {
+        match entry {
+            BackendEntry::Addr(addr) => BackendConfig::new(addr),
+            BackendEntry::Full {
+                addr,
+                weight,
+                health_check,
+                drain,
+            } => BackendConfig {
+                addr,
+                weight,
+                health_check,
+                drain,
+            },
+        }
+    }
+}
+
+/// Upstream connection to a `Backend`, checked out from its pool with `Backend::connection` and
+/// handed back with `Backend::release` once the response has been completely read.
+#[derive(Debug)]
+pub struct Connection {
+    pub stream: BufReader<TcpStream>,
+    created: Instant,
+    reused: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Connection {
+    /// Return `true` if the connection already served other requests, in which case the
+    /// backend may have closed it in the mean-time
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+}
+
+#[derive(Debug)]
+struct IdleConnection {
+    stream: BufReader<TcpStream>,
+    created: Instant,
+    idle_since: Instant,
+}
+
+#[derive(Debug)]
+struct ConnectionPool {
+    config: ConnectionPoolConfig,
+    idle: Mutex<Vec<IdleConnection>>,
+    permits: Arc<Semaphore>,
+}
+
+impl ConnectionPool {
+    fn new(config: ConnectionPoolConfig) -> ConnectionPool {
+        ConnectionPool {
+            config,
+            idle: Mutex::new(Vec::new()),
+            permits: Arc::new(Semaphore::new(config.max_connections)),
+        }
+    }
+
+    /// Pop the most recently used idle connection still valid, closing the expired ones met on
+    /// the way
+    fn pop_idle(&self) -> Option<IdleConnection> {
+        let max_lifetime = Duration::from_millis(self.config.max_lifetime);
+        let idle_timeout = Duration::from_millis(self.config.idle_timeout);
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(conn) = idle.pop() {
+            if conn.created.elapsed() < max_lifetime && conn.idle_since.elapsed() < idle_timeout {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn push_idle(&self, conn: Connection) {
+        let max_lifetime = Duration::from_millis(self.config.max_lifetime);
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.config.max_idle && conn.created.elapsed() < max_lifetime {
+            idle.push(IdleConnection {
+                stream: conn.stream,
+                created: conn.created,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    fn idle_len(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
 #[derive(Debug)]
 pub struct Backend {
     pub addr: String,
-    pub alive: AtomicBool,
+    health: Health,
+    outlier: Outlier,
     byte_traffic: AtomicUsize,
-    health_endpoint: Option<String>,
+    in_flight: AtomicUsize,
+    ttfb: Ewma,
+    latency: Ewma,
+    weight: u32,
+    health_check: HealthCheck,
+    connections: ConnectionPool,
 }
 
 impl Backend {
@@ -35,24 +280,193 @@ impl Backend {
     /// The addr is the connection endpoint representing the backend, health_endpoint is an
     /// `Option` representing an optional healthcheck endpoint
     pub fn new(addr: String, health_endpoint: Option<String>) -> Backend {
+        Backend::with_connection_pool(addr, health_endpoint, ConnectionPoolConfig::default())
+    }
+
+    /// Create a new Backend with custom limits for its pool of upstream connections
+    pub fn with_connection_pool(
+        addr: String,
+        health_endpoint: Option<String>,
+        pool_config: ConnectionPoolConfig,
+    ) -> Backend {
         Backend {
             addr,
-            alive: AtomicBool::new(false),
+            health: Health::new(),
+            outlier: Outlier::new(),
             byte_traffic: AtomicUsize::new(0),
-            health_endpoint,
+            in_flight: AtomicUsize::new(0),
+            ttfb: Ewma::new(),
+            latency: Ewma::new(),
+            weight: 1,
+            health_check: HealthCheck {
+                path: health_endpoint,
+                ..HealthCheck::default()
+            },
+            connections: ConnectionPool::new(pool_config),
         }
     }
 
-    pub fn set_online(&mut self) {
-        self.alive.store(true, Ordering::Relaxed);
+    /// Create a new Backend from its configuration
+    ///
+    /// `health_check` is the default check of the pool, used if the backend doesn't set its own.
+    pub fn from_config(
+        config: &BackendConfig,
+        pool_config: ConnectionPoolConfig,
+        health_check: &HealthCheck,
+    ) -> Backend {
+        let mut backend = Backend::with_connection_pool(config.addr.clone(), None, pool_config);
+        backend.set_weight(config.weight);
+        backend.set_health_check(config.health_check.as_ref().unwrap_or(health_check).clone());
+        if config.drain {
+            backend.drain();
+        }
+        backend
     }
 
-    pub fn set_offline(&mut self) {
-        self.alive.store(false, Ordering::Relaxed);
+    pub fn weight(&self) -> u32 {
+        self.weight
     }
 
-    pub fn increase_byte_traffic(&mut self, bytes: usize) {
-        self.byte_traffic.store(bytes, Ordering::Relaxed);
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+
+    /// Return true if the backend can be selected to serve new requests: it's healthy, not
+    /// drained by a weight of 0 and not ejected for failing on live traffic.
+    pub fn is_available(&self) -> bool {
+        self.weight > 0 && self.is_alive() && !self.is_ejected()
+    }
+
+    /// Return true if the backend is ejected by the outlier detection
+    pub fn is_ejected(&self) -> bool {
+        self.outlier.is_ejected()
+    }
+
+    /// Record the outcome of a request forwarded to the backend, return true if according to
+    /// `config` the backend is failing and should be ejected.
+    pub fn record_outcome(&self, success: bool, config: &OutlierDetection) -> bool {
+        self.outlier.record(success, config)
+    }
+
+    /// Eject the backend for a time growing with the number of its recent ejections, return
+    /// the duration of the ejection.
+    pub fn eject(&self, config: &OutlierDetection) -> Duration {
+        self.outlier.eject(config)
+    }
+
+    /// Return true if the backend is healthy
+    pub fn is_alive(&self) -> bool {
+        self.health.state() == HealthState::Healthy
+    }
+
+    pub fn health_state(&self) -> HealthState {
+        self.health.state()
+    }
+
+    /// Record the outcome of a health check of the backend, following the `rise` and `fall`
+    /// thresholds of its health check configuration. Return the previous and the new state if
+    /// the health state of the backend changed.
+    pub fn record_health_check(&self, success: bool) -> Option<(HealthState, HealthState)> {
+        self.health
+            .record(success, self.health_check.rise, self.health_check.fall)
+    }
+
+    /// Stop sending new requests to the backend, until it's set online again
+    pub fn drain(&self) {
+        self.health.set(HealthState::Draining);
+    }
+
+    /// Return a connection to the backend, reusing an idle one from the pool if available or
+    /// opening a new one otherwise. Waits for a connection to be released if `max_connections`
+    /// are already in use.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err` if a new connection to the backend can't be established.
+    pub async fn connection(&self) -> io::Result<Connection> {
+        let permit = self.connections.permits.clone().acquire_owned().await;
+        if let Some(conn) = self.connections.pop_idle() {
+            return Ok(Connection {
+                stream: conn.stream,
+                created: conn.created,
+                reused: true,
+                _permit: permit,
+            });
+        }
+        let stream = TcpStream::connect(self.addr.as_str()).await?;
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            created: Instant::now(),
+            reused: false,
+            _permit: permit,
+        })
+    }
+
+    /// Hand a connection back to the backend pool, to be reused by following requests. Must be
+    /// called only once the connection is ready to carry a new request, connections not
+    /// released are closed on drop. Connections holding bytes not read yet, e.g. an extra
+    /// response sent by the backend, are closed as well, not to be read as the response to
+    /// another request.
+    pub fn release(&self, conn: Connection) {
+        if conn.stream.buffer().is_empty() {
+            self.connections.push_idle(conn);
+        }
+    }
+
+    /// Return the number of idle connections ready to be reused
+    pub fn idle_connections(&self) -> usize {
+        self.connections.idle_len()
+    }
+
+    pub fn set_online(&self) {
+        self.health.set(HealthState::Healthy);
+    }
+
+    pub fn set_offline(&self) {
+        self.health.set(HealthState::Unhealthy);
+    }
+
+    pub fn increase_byte_traffic(&self, bytes: usize) {
+        self.byte_traffic.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Return the number of upstream connections to the backend currently in use, idle pooled
+    /// connections are not counted.
+    pub fn active_connections(&self) -> usize {
+        self.connections
+            .config
+            .max_connections
+            .saturating_sub(self.connections.permits.available_permits())
+    }
+
+    /// Mark the start of a request forwarded to the backend, the request counts as in flight
+    /// until the returned guard is dropped.
+    pub fn start_request(&self) -> InFlightRequest<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightRequest { backend: self }
+    }
+
+    /// Record the timings of a request served by the backend: the time until the head of the
+    /// response was received and the time until its body was completely streamed.
+    pub fn record_latency(&self, ttfb: Duration, total: Duration) {
+        self.ttfb.observe(ttfb);
+        self.latency.observe(total);
+    }
+
+    /// Return the moving average of the time to first byte of the responses, in milliseconds
+    pub fn ttfb(&self) -> f64 {
+        self.ttfb.value()
+    }
+
+    /// Return the moving average of the total duration of the requests, in milliseconds
+    pub fn latency(&self) -> f64 {
+        self.latency.value()
+    }
+
+    /// Return the number of requests forwarded to the backend still waiting for the response
+    /// to be completely streamed back to the client.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
     }
 
     pub fn byte_traffic(&self) -> usize {
@@ -60,13 +474,22 @@ impl Backend {
     }
 
     pub fn health_endpoint(&self) -> &Option<String> {
-        &self.health_endpoint
+        &self.health_check.path
+    }
+
+    pub fn health_check(&self) -> &HealthCheck {
+        &self.health_check
+    }
+
+    pub fn set_health_check(&mut self, health_check: HealthCheck) {
+        self.health_check = health_check;
     }
 }
 
 pub struct BackendPool {
     backends: Vec<Backend>,
     balancing_algo: Box<dyn LoadBalancing + Send + Sync>,
+    outlier_detection: OutlierDetection,
 }
 
 impl BackendPool {
@@ -75,6 +498,7 @@ impl BackendPool {
         BackendPool {
             backends: Vec::new(),
             balancing_algo,
+            outlier_detection: OutlierDetection::default(),
         }
     }
 
@@ -82,6 +506,10 @@ impl BackendPool {
         self.backends.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
     pub fn from_backends_list(
         backends: Vec<Backend>,
         balancing_algo: Box<dyn LoadBalancing + Send + Sync>,
@@ -89,6 +517,7 @@ impl BackendPool {
         BackendPool {
             backends,
             balancing_algo,
+            outlier_detection: OutlierDetection::default(),
         }
     }
 
@@ -96,30 +525,88 @@ impl BackendPool {
         self.backends.push(backend);
     }
 
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<Backend> {
-        self.backends.iter_mut()
+    pub fn iter(&self) -> std::slice::Iter<'_, Backend> {
+        self.backends.iter()
     }
 
-    pub fn next_backend(&mut self) -> Result<usize, BackendError> {
-        let mut index = None;
-        // Loop until an available backend is found, checking at every run that
-        // there's at least one alive backend to avoid looping forever
-        loop {
-            if !self.has_backends_available() {
-                break;
-            }
-            if let Some(i) = self.balancing_algo.next_backend(&self.backends) {
-                index = Some(i);
-                break;
-            }
+    pub fn outlier_detection(&self) -> &OutlierDetection {
+        &self.outlier_detection
+    }
+
+    pub fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.outlier_detection = outlier_detection;
+    }
+
+    /// Record the outcome of a request forwarded to the backend at `index`, ejecting it if
+    /// it's failing, unless that would leave more than `max_ejection_percent` percent of the
+    /// backends ejected.
+    ///
+    /// Return the duration of the ejection if the backend has been ejected.
+    pub fn record_outcome(&self, index: usize, success: bool) -> Option<Duration> {
+        let backend = &self.backends[index];
+        if !backend.record_outcome(success, &self.outlier_detection) {
+            return None;
+        }
+        let ejected = self.backends.iter().filter(|b| b.is_ejected()).count();
+        if (ejected + 1) * 100 > self.outlier_detection.max_ejection_percent as usize * self.len() {
+            warn!(
+                "Backend {} is failing, not ejected as {} backends are ejected already",
+                backend.addr, ejected
+            );
+            return None;
+        }
+        let duration = backend.eject(&self.outlier_detection);
+        warn!(
+            "Backend {} is failing, ejected for {}ms",
+            backend.addr,
+            duration.as_millis()
+        );
+        Some(duration)
+    }
+
+    /// Select the index of the next backend to forward a request to, according to the
+    /// balancing algorithm of the pool.
+    ///
+    /// Only needs a shared reference, as the state of both the backends and the balancing
+    /// algorithms is made of atomics and short locks only, so the pool can be shared by all the
+    /// connections.
+    ///
+    /// # Errors
+    ///
+    /// Return `Err(BackendError::NoBackendAlive)` if all the backends are offline or drained.
+    pub fn next_backend(&self) -> Result<usize, BackendError> {
+        self.select(None, &[])
+    }
+
+    /// Select the index of the backend to forward the request described by `ctx` to, allowing
+    /// the balancing algorithm to route on the content of the request.
+    pub fn next_backend_for(&self, ctx: &RequestContext<'_>) -> Result<usize, BackendError> {
+        self.select(Some(ctx), &[])
+    }
+
+    /// Select the index of the next backend to retry a request on, among the available
+    /// backends not in `excluded`, the ones the request already failed on.
+    pub fn next_backend_excluding(&self, excluded: &[usize]) -> Result<usize, BackendError> {
+        self.select(None, excluded)
+    }
+
+    /// The balancing algorithm only chooses among the available backends, so the selection is
+    /// made by a single call, whatever the state of the backends.
+    fn select(
+        &self,
+        ctx: Option<&RequestContext<'_>>,
+        excluded: &[usize],
+    ) -> Result<usize, BackendError> {
+        let mut candidates = balancing::candidates(&self.backends);
+        candidates.retain(|i| !excluded.contains(i));
+        if candidates.is_empty() {
+            return Err(BackendError::NoBackendAlive);
         }
-        index.map_or(Err(BackendError::NoBackendAlive), |i| Ok(i))
+        Ok(self.balancing_algo.select(&self.backends, &candidates, ctx))
     }
 
     pub fn has_backends_available(&self) -> bool {
-        self.backends
-            .iter()
-            .any(|b| b.alive.load(Ordering::Relaxed) == true)
+        self.backends.iter().any(|b| b.is_available())
     }
 }
 
@@ -129,9 +616,3 @@ impl Index<usize> for BackendPool {
         &self.backends[index]
     }
 }
-
-impl IndexMut<usize> for BackendPool {
-    fn index_mut(&mut self, index: usize) -> &mut Backend {
-        &mut self.backends[index]
-    }
-}
@@ -1,19 +1,32 @@
 /// Balancing algorithms between a vector of backend items.
 ///
 /// Provides a public trait `LoadBalancing`, every different balancing algorithm implements this
-/// trait exposing `next_backend` method.
+/// trait exposing `select` method, choosing among the available backends only.
 use crate::backend::Backend;
 use crate::http::HttpMessage;
 use rand::Rng;
 use serde::Deserialize;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
 
+#[derive(Debug, PartialEq)]
 pub enum BalancingError {
     UnknownAlgorithm,
 }
 
+impl fmt::Display for BalancingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BalancingError::UnknownAlgorithm => write!(f, "Unknown balancing algorithm"),
+        }
+    }
+}
+
+impl Error for BalancingError {}
+
 /// Supported balancing algorithm types
 #[derive(Debug, PartialEq, Deserialize)]
 pub enum BalancingAlgorithm {
@@ -23,8 +36,26 @@ pub enum BalancingAlgorithm {
     Random,
     #[serde(rename(deserialize = "least-traffic"))]
     LeastTraffic,
+    #[serde(rename(deserialize = "weighted-round-robin"))]
+    WeightedRoundRobin,
+    #[serde(rename(deserialize = "weighted-random"))]
+    WeightedRandom,
+    #[serde(rename(deserialize = "least-connections"))]
+    LeastConnections,
+    #[serde(rename(deserialize = "weighted-least-connections"))]
+    WeightedLeastConnections,
+    #[serde(rename(deserialize = "least-requests"))]
+    LeastRequests,
+    #[serde(rename(deserialize = "p2c"))]
+    PowerOfTwoChoices,
+    #[serde(rename(deserialize = "least-latency"))]
+    LeastLatency,
     #[serde(rename(deserialize = "hashing"))]
     Hashing,
+    #[serde(rename(deserialize = "consistent-hashing"))]
+    ConsistentHashing,
+    #[serde(rename(deserialize = "rendezvous"))]
+    Rendezvous,
 }
 
 impl BalancingAlgorithm {
@@ -40,9 +71,181 @@ impl BalancingAlgorithm {
         BalancingAlgorithm::LeastTraffic
     }
 
+    pub fn weighted_round_robin() -> Self {
+        BalancingAlgorithm::WeightedRoundRobin
+    }
+
+    pub fn weighted_random() -> Self {
+        BalancingAlgorithm::WeightedRandom
+    }
+
+    pub fn least_connections() -> Self {
+        BalancingAlgorithm::LeastConnections
+    }
+
+    pub fn weighted_least_connections() -> Self {
+        BalancingAlgorithm::WeightedLeastConnections
+    }
+
+    pub fn least_requests() -> Self {
+        BalancingAlgorithm::LeastRequests
+    }
+
+    pub fn power_of_two_choices() -> Self {
+        BalancingAlgorithm::PowerOfTwoChoices
+    }
+
+    pub fn least_latency() -> Self {
+        BalancingAlgorithm::LeastLatency
+    }
+
     pub fn hashing() -> Self {
         BalancingAlgorithm::Hashing
     }
+
+    pub fn consistent_hashing() -> Self {
+        BalancingAlgorithm::ConsistentHashing
+    }
+
+    pub fn rendezvous() -> Self {
+        BalancingAlgorithm::Rendezvous
+    }
+}
+
+/// Part of the request the hashing algorithms compute the hash on, requests sharing the same
+/// key are routed to the same backend as long as it's alive.
+///
+/// In the configuration it's either a plain string (`client-ip` or `path`) or a map naming
+/// the header, cookie or query parameter to use, e.g. `hash_key: { header: "X-User-Id" }`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub enum HashKey {
+    #[default]
+    #[serde(rename(deserialize = "client-ip"))]
+    ClientIp,
+    #[serde(rename(deserialize = "path"))]
+    Path,
+    #[serde(rename(deserialize = "header"))]
+    Header(String),
+    #[serde(rename(deserialize = "cookie"))]
+    Cookie(String),
+    #[serde(rename(deserialize = "query"))]
+    Query(String),
+}
+
+impl HashKey {
+    /// Extract the key value from the request context, return `None` if the request doesn't
+    /// carry it (e.g. missing header or cookie).
+    pub fn extract<'a>(&self, ctx: &RequestContext<'a>) -> Option<String> {
+        match self {
+            HashKey::ClientIp => ctx.client_addr.map(|ip| ip.to_string()),
+            HashKey::Path => ctx
+                .request
+                .route()
+                .map(|r| r.split('?').next().unwrap_or("").to_string()),
+            HashKey::Header(name) => ctx.request.headers.get(name).map(|v| v.to_string()),
+            HashKey::Cookie(name) => ctx
+                .request
+                .headers
+                .get_all("Cookie")
+                .flat_map(|v| v.split(';'))
+                .filter_map(|pair| find_pair(pair, name))
+                .next(),
+            HashKey::Query(name) => ctx
+                .request
+                .route()
+                .and_then(|r| r.split_once('?').map(|(_, q)| q))
+                .and_then(|q| q.split('&').filter_map(|pair| find_pair(pair, name)).next()),
+        }
+    }
+}
+
+/// Return the key to hash the request on, falling back to the client address for requests
+/// missing the configured key
+fn request_key(key: &HashKey, ctx: &RequestContext<'_>) -> String {
+    key.extract(ctx)
+        .or_else(|| HashKey::ClientIp.extract(ctx))
+        .unwrap_or_default()
+}
+
+/// Return the value of a `name=value` pair if the name matches
+fn find_pair(pair: &str, name: &str) -> Option<String> {
+    match pair.trim().split_once('=') {
+        Some((n, v)) if n == name => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Measure of the load of a backend compared by the power of two choices algorithm
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum LoadMetric {
+    /// Requests forwarded to the backend still waiting for the response to complete
+    #[default]
+    #[serde(rename(deserialize = "in-flight"))]
+    InFlight,
+    /// Upstream connections to the backend in use
+    #[serde(rename(deserialize = "connections"))]
+    Connections,
+    /// Peak-EWMA cost: moving average of the time to first byte, multiplied by the requests in
+    /// flight plus one. Backends not measured yet cost the average of the others for each request
+    /// in flight, so that idle ones are tried first without all the traffic going to them.
+    #[serde(rename(deserialize = "latency"))]
+    Latency,
+}
+
+impl LoadMetric {
+    /// Return the current load of `backend`. `default_ttfb` is the time to first byte in
+    /// milliseconds counted by `LoadMetric::Latency` if the backend has no sample yet.
+    pub fn load(&self, backend: &Backend, default_ttfb: f64) -> u64 {
+        match self {
+            LoadMetric::InFlight => backend.in_flight() as u64,
+            LoadMetric::Connections => backend.active_connections() as u64,
+            LoadMetric::Latency => {
+                let in_flight = backend.in_flight() as u64;
+                // Microseconds, to keep some precision on fast backends
+                match backend.ttfb() {
+                    ttfb if ttfb > 0.0 => (ttfb * 1000.0) as u64 * (in_flight + 1),
+                    _ => (default_ttfb * 1000.0) as u64 * in_flight,
+                }
+            }
+        }
+    }
+}
+
+/// Return the average time to first byte in milliseconds of the measured `candidates`, or 1ms if
+/// none of them has a sample yet
+fn average_ttfb(backends: &[Backend], candidates: &[usize]) -> f64 {
+    let (sum, count) = candidates
+        .iter()
+        .map(|&i| backends[i].ttfb())
+        .filter(|ttfb| *ttfb > 0.0)
+        .fold((0.0, 0), |(sum, count), ttfb| (sum + ttfb, count + 1));
+    if count == 0 {
+        1.0
+    } else {
+        sum / f64::from(count)
+    }
+}
+
+/// Tuning of the balancing algorithms, part of the configuration
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BalancingOptions {
+    /// Key the hashing algorithms hash the requests on
+    pub hash_key: HashKey,
+    /// Number of points each backend owns on the consistent hashing ring
+    pub virtual_nodes: usize,
+    /// Load compared between the two backends sampled by the power of two choices algorithm
+    pub p2c_load: LoadMetric,
+}
+
+impl Default for BalancingOptions {
+    fn default() -> Self {
+        BalancingOptions {
+            hash_key: HashKey::default(),
+            virtual_nodes: 160,
+            p2c_load: LoadMetric::default(),
+        }
+    }
 }
 
 /// Factory function, used to create the `BalancingAlgorithm` based on the requested type
@@ -53,24 +256,106 @@ impl BalancingAlgorithm {
 /// argument.
 pub fn get_balancer(
     balancing_algo: &BalancingAlgorithm,
+    options: &BalancingOptions,
 ) -> Result<Box<dyn LoadBalancing + Send + Sync>, BalancingError> {
     match balancing_algo {
         BalancingAlgorithm::RoundRobin => Ok(Box::new(RoundRobinBalancing::new())),
         BalancingAlgorithm::Random => Ok(Box::new(RandomBalancing::new())),
         BalancingAlgorithm::LeastTraffic => Ok(Box::new(LeastTrafficBalancing::new())),
-        _ => Err(BalancingError::UnknownAlgorithm),
+        BalancingAlgorithm::WeightedRoundRobin => Ok(Box::new(WeightedRoundRobinBalancing::new())),
+        BalancingAlgorithm::WeightedRandom => Ok(Box::new(WeightedRandomBalancing::new())),
+        BalancingAlgorithm::LeastConnections => Ok(Box::new(LeastConnectionsBalancing::new(false))),
+        BalancingAlgorithm::WeightedLeastConnections => {
+            Ok(Box::new(LeastConnectionsBalancing::new(true)))
+        }
+        BalancingAlgorithm::LeastRequests => Ok(Box::new(LeastRequestsBalancing::new())),
+        BalancingAlgorithm::LeastLatency => Ok(Box::new(LeastLatencyBalancing::new())),
+        BalancingAlgorithm::PowerOfTwoChoices => {
+            Ok(Box::new(PowerOfTwoChoicesBalancing::new(options.p2c_load)))
+        }
+        BalancingAlgorithm::Hashing => {
+            Ok(Box::new(HashingBalancing::new(options.hash_key.clone())))
+        }
+        BalancingAlgorithm::ConsistentHashing => Ok(Box::new(ConsistentHashing::new(
+            options.hash_key.clone(),
+            options.virtual_nodes,
+        ))),
+        BalancingAlgorithm::Rendezvous => {
+            Ok(Box::new(RendezvousHashing::new(options.hash_key.clone())))
+        }
     }
 }
 
-/// Generic balancing algorithm trait. Exposes only one method `next_backend` which take a
-/// reference to a `Vec<Backend>` type.
+/// Information about the request being balanced, available to the algorithms routing on the
+/// request content
+pub struct RequestContext<'a> {
+    /// The request head read from the client
+    pub request: &'a HttpMessage,
+    /// Address of the client that sent the request, if known
+    pub client_addr: Option<IpAddr>,
+}
+
+impl<'a> RequestContext<'a> {
+    pub fn new(request: &'a HttpMessage, client_addr: Option<IpAddr>) -> RequestContext<'a> {
+        RequestContext {
+            request,
+            client_addr,
+        }
+    }
+}
+
+/// Return the indexes of the backends that can be selected to serve new requests, alive and not
+/// drained, in order.
+pub fn candidates(backends: &[Backend]) -> Vec<usize> {
+    backends
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.is_available())
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Generic balancing algorithm trait. Algorithms implement `select`, which picks a backend among
+/// a list of candidates already filtered by availability, so a selection always succeeds in a
+/// single call as long as one backend is available. `next_backend` and `next_backend_for` are
+/// the entry points computing the candidates from a slice of `Backend`.
 pub trait LoadBalancing {
-    /// Return the first valid backend index in the vector according to the heuristic the algorithm
-    /// represents. Requires `mut self` as some algorithms need to store a state that must be
-    /// updated at every call.
-    fn next_backend(&mut self, backends: &Vec<Backend>) -> Option<usize>;
+    /// Return the index of the backend to forward the request to according to the heuristic
+    /// the algorithm represents. `candidates` holds the indexes in `backends` of the available
+    /// backends, it's never empty and the index returned must be one of them. `ctx` describes
+    /// the request being balanced, when known.
+    ///
+    /// Takes `&self` as the selection runs concurrently from every connection, algorithms that
+    /// need to store a state updated at every call must rely on atomics or short locks.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        ctx: Option<&RequestContext<'_>>,
+    ) -> usize;
+
+    /// Return the index of the next available backend, `None` if all backends are offline or
+    /// drained.
+    fn next_backend(&self, backends: &[Backend]) -> Option<usize> {
+        let candidates = candidates(backends);
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(self.select(backends, &candidates, None))
+    }
+
+    /// Return the index of the available backend to route the request described by `ctx` to,
+    /// `None` if all backends are offline or drained.
+    fn next_backend_for(&self, backends: &[Backend], ctx: &RequestContext<'_>) -> Option<usize> {
+        let candidates = candidates(backends);
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(self.select(backends, &candidates, Some(ctx)))
+    }
 }
 
+#[derive(Default)]
 pub struct RoundRobinBalancing {
     next_index: AtomicUsize,
 }
@@ -85,21 +370,14 @@ impl RoundRobinBalancing {
 }
 
 impl LoadBalancing for RoundRobinBalancing {
-    /// Find an available backend from a vector of `Backend` type objects.
-    ///
-    /// Returns an `Option<usize>` with the possible index of the next available
-    /// backend, if all backends are offline (alive == false) return None.
-    fn next_backend(&mut self, backends: &Vec<Backend>) -> Option<usize> {
-        let index = self.next_index.load(Ordering::Acquire) % backends.len();
-        self.next_index.store(index + 1, Ordering::Relaxed);
-        if backends[index].alive.load(Ordering::Acquire) {
-            Some(index)
-        } else {
-            None
-        }
+    /// Rotate over the available backends.
+    fn select(&self, _: &[Backend], candidates: &[usize], _: Option<&RequestContext<'_>>) -> usize {
+        let next = self.next_index.fetch_add(1, Ordering::Relaxed);
+        candidates[next % candidates.len()]
     }
 }
 
+#[derive(Default)]
 pub struct RandomBalancing;
 
 impl RandomBalancing {
@@ -109,21 +387,91 @@ impl RandomBalancing {
 }
 
 impl LoadBalancing for RandomBalancing {
-    /// Return a randomly choosen backend, the only restriction followed is that
-    /// it must be alive and healthy.
-    ///
-    /// Returns an `Option<usize>` with the possible index of the next available
-    /// backend, if all backends are offline (alive == false) return None.
-    fn next_backend(&mut self, backends: &Vec<Backend>) -> Option<usize> {
-        let index = rand::thread_rng().gen_range(0, backends.len());
-        if backends[index].alive.load(Ordering::Acquire) {
-            Some(index)
-        } else {
-            None
+    /// Return a randomly choosen backend among the available ones.
+    fn select(&self, _: &[Backend], candidates: &[usize], _: Option<&RequestContext<'_>>) -> usize {
+        candidates[rand::thread_rng().gen_range(0, candidates.len())]
+    }
+}
+
+/// Smooth weighted round-robin, as implemented by nginx: at every selection each available
+/// backend increases its current weight by its configured weight, the one with the highest
+/// current weight is selected and has its current weight decreased by the total. A backend
+/// of weight 3 and one of weight 1 are selected as `a a b a` rather than `a a a b`.
+#[derive(Default)]
+pub struct WeightedRoundRobinBalancing {
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl WeightedRoundRobinBalancing {
+    pub fn new() -> WeightedRoundRobinBalancing {
+        WeightedRoundRobinBalancing {
+            current_weights: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl LoadBalancing for WeightedRoundRobinBalancing {
+    /// Select the available backend with the highest current weight, backends with a weight
+    /// of 0 are never candidates.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        _: Option<&RequestContext<'_>>,
+    ) -> usize {
+        let mut current_weights = self.current_weights.lock().unwrap();
+        current_weights.resize(backends.len(), 0);
+        let mut total = 0;
+        let mut best = candidates[0];
+        for (n, &i) in candidates.iter().enumerate() {
+            let weight = i64::from(backends[i].weight());
+            current_weights[i] += weight;
+            total += weight;
+            if n == 0 || current_weights[i] > current_weights[best] {
+                best = i;
+            }
         }
+        current_weights[best] -= total;
+        best
     }
 }
 
+#[derive(Default)]
+pub struct WeightedRandomBalancing;
+
+impl WeightedRandomBalancing {
+    pub fn new() -> WeightedRandomBalancing {
+        WeightedRandomBalancing {}
+    }
+}
+
+impl LoadBalancing for WeightedRandomBalancing {
+    /// Return a randomly choosen available backend, each one with a probability proportional
+    /// to its weight.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        _: Option<&RequestContext<'_>>,
+    ) -> usize {
+        let weight = |i: usize| u64::from(backends[i].weight());
+        let total: u64 = candidates.iter().map(|&i| weight(i)).sum();
+        // The weights could have changed since the candidates were filtered
+        if total == 0 {
+            return candidates[0];
+        }
+        let mut point = rand::thread_rng().gen_range(0, total);
+        for &i in candidates {
+            if point < weight(i) {
+                return i;
+            }
+            point -= weight(i);
+        }
+        candidates[candidates.len() - 1]
+    }
+}
+
+#[derive(Default)]
 pub struct LeastTrafficBalancing;
 
 impl LeastTrafficBalancing {
@@ -133,59 +481,363 @@ impl LeastTrafficBalancing {
 }
 
 impl LoadBalancing for LeastTrafficBalancing {
-    /// Find an available backend from a vector of `Backend` type objects based
-    /// on their traffic bytes count.
-    ///
-    /// Returns an `Option<usize>` with the possible index of the next available
-    /// backend, if all backends are offline (alive == false) return None.
-    fn next_backend(&mut self, backends: &Vec<Backend>) -> Option<usize> {
+    /// Find the available backend with the lowest traffic bytes count.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        _: Option<&RequestContext<'_>>,
+    ) -> usize {
         // Just find the index of the backend with the min value of `bytes_traffic`
         // field
-        let index = backends
+        *candidates
             .iter()
-            .enumerate()
-            .min_by_key(|(_, b)| b.byte_traffic())
-            .map(|(i, _)| i)
-            .unwrap();
-        if backends[index].alive.load(Ordering::Acquire) {
-            Some(index)
-        } else {
-            None
+            .min_by_key(|&&i| backends[i].byte_traffic())
+            .unwrap()
+    }
+}
+
+/// Return the candidate with the lowest `load`, divided by the weight of the backend if
+/// `weighted`. The scan starts from `offset`, so that ties are broken rotating between the
+/// backends rather than always favouring the first one.
+fn least_loaded<F>(
+    backends: &[Backend],
+    candidates: &[usize],
+    offset: usize,
+    weighted: bool,
+    load: F,
+) -> usize
+where
+    F: Fn(&Backend) -> u64,
+{
+    let weight = |b: &Backend| if weighted { u64::from(b.weight()) } else { 1 };
+    let mut best: Option<(usize, u64, u64)> = None;
+    for n in 0..candidates.len() {
+        let i = candidates[(n + offset) % candidates.len()];
+        let (l, w) = (load(&backends[i]), weight(&backends[i]));
+        // l / w < best_l / best_w, compared without divisions
+        if best.is_none_or(|(_, best_l, best_w)| l * best_w < best_l * w) {
+            best = Some((i, l, w));
         }
     }
+    best.map_or(candidates[0], |(i, _, _)| i)
 }
 
-pub struct HashingBalancing<'a> {
-    request: &'a HttpMessage,
+/// Select the backend with the fewest upstream connections in use, optionally weighted, that is
+/// the lowest ratio of connections to weight.
+#[derive(Default)]
+pub struct LeastConnectionsBalancing {
+    weighted: bool,
+    offset: AtomicUsize,
 }
 
-impl<'a> HashingBalancing<'a> {
-    pub fn new(request: &'a HttpMessage) -> HashingBalancing<'a> {
-        HashingBalancing { request }
+impl LeastConnectionsBalancing {
+    /// Create a new LeastConnectionsBalancing algorithm, taking into account the weight of the
+    /// backends if `weighted` is true.
+    pub fn new(weighted: bool) -> LeastConnectionsBalancing {
+        LeastConnectionsBalancing {
+            weighted,
+            offset: AtomicUsize::new(0),
+        }
     }
 }
 
-impl<'a> LoadBalancing for HashingBalancing<'a> {
-    /// Find an available backend from a vector of `Backend` type objects based
-    /// on the request hash computed.
-    ///
-    /// Returns an `Option<usize>` with the possible index of the next available
-    /// backend, if all backends are offline (alive == false) return None.
-    fn next_backend(&mut self, backends: &Vec<Backend>) -> Option<usize> {
-        // Just find the index of the backend with the min value of `bytes_traffic`
-        // field
-        let mut s = DefaultHasher::new();
-        let index = match self.request.method() {
-            Some(m) => {
-                m.hash(&mut s);
-                s.finish() as usize % backends.len()
-            }
-            None => return None,
+impl LoadBalancing for LeastConnectionsBalancing {
+    /// Find the available backend with the least active connections.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        _: Option<&RequestContext<'_>>,
+    ) -> usize {
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed);
+        least_loaded(backends, candidates, offset, self.weighted, |b| {
+            b.active_connections() as u64
+        })
+    }
+}
+
+/// Select the backend with the fewest outstanding requests, that is requests forwarded to it
+/// whose response is not completely streamed back to the client yet.
+#[derive(Default)]
+pub struct LeastRequestsBalancing {
+    offset: AtomicUsize,
+}
+
+impl LeastRequestsBalancing {
+    pub fn new() -> LeastRequestsBalancing {
+        LeastRequestsBalancing {
+            offset: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancing for LeastRequestsBalancing {
+    /// Find the available backend with the least requests in flight.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        _: Option<&RequestContext<'_>>,
+    ) -> usize {
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed);
+        least_loaded(backends, candidates, offset, false, |b| {
+            b.in_flight() as u64
+        })
+    }
+}
+
+/// Peak-EWMA balancing: select the backend with the lowest moving average of the time to first
+/// byte, weighted by the requests in flight, steering the traffic away from slow backends.
+/// Backends with no latency samples yet are preferred, so they get some traffic to be measured.
+#[derive(Default)]
+pub struct LeastLatencyBalancing {
+    offset: AtomicUsize,
+}
+
+impl LeastLatencyBalancing {
+    pub fn new() -> LeastLatencyBalancing {
+        LeastLatencyBalancing {
+            offset: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancing for LeastLatencyBalancing {
+    /// Find the available backend with the lowest latency cost.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        _: Option<&RequestContext<'_>>,
+    ) -> usize {
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed);
+        let default_ttfb = average_ttfb(backends, candidates);
+        least_loaded(backends, candidates, offset, false, |b| {
+            LoadMetric::Latency.load(b, default_ttfb)
+        })
+    }
+}
+
+/// Power of two choices: sample two random available backends and select the least loaded of
+/// the two. Close to the least loaded backend on average, without sending every new request to
+/// the same backend as comparing all of them does.
+pub struct PowerOfTwoChoicesBalancing {
+    metric: LoadMetric,
+}
+
+impl PowerOfTwoChoicesBalancing {
+    /// Create a new PowerOfTwoChoicesBalancing algorithm comparing backends on `metric`.
+    pub fn new(metric: LoadMetric) -> PowerOfTwoChoicesBalancing {
+        PowerOfTwoChoicesBalancing { metric }
+    }
+}
+
+impl LoadBalancing for PowerOfTwoChoicesBalancing {
+    /// Select the less loaded of two distinct random available backends, or the only one
+    /// available.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        _: Option<&RequestContext<'_>>,
+    ) -> usize {
+        if candidates.len() == 1 {
+            return candidates[0];
+        }
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0, candidates.len());
+        // Draw the second among the other candidates
+        let second = (first + rng.gen_range(1, candidates.len())) % candidates.len();
+        let (first, second) = (candidates[first], candidates[second]);
+        // Only the two sampled backends are looked at, to keep the selection cheap
+        let default_ttfb = match self.metric {
+            LoadMetric::Latency => average_ttfb(backends, &[first, second]),
+            _ => 0.0,
         };
-        if backends[index].alive.load(Ordering::Acquire) {
-            Some(index)
+        let load = |i: usize| self.metric.load(&backends[i], default_ttfb);
+        if load(second) < load(first) {
+            second
         } else {
-            None
+            first
         }
     }
 }
+
+/// Modulo hashing, every key maps to the backend at `hash % backends`. Simple but changing the
+/// number of backends remaps almost every key, see `ConsistentHashing` for an alternative.
+pub struct HashingBalancing {
+    key: HashKey,
+}
+
+impl HashingBalancing {
+    /// Create a new HashingBalancing algorithm hashing the requests on `key`.
+    pub fn new(key: HashKey) -> HashingBalancing {
+        HashingBalancing { key }
+    }
+}
+
+impl LoadBalancing for HashingBalancing {
+    /// Find the backend the hash of the request key maps to. If it's not available the key is
+    /// mapped again on the available backends only. Without a request all the traffic maps to
+    /// the same backend.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        ctx: Option<&RequestContext<'_>>,
+    ) -> usize {
+        let key = ctx
+            .map(|ctx| request_key(&self.key, ctx))
+            .unwrap_or_default();
+        // Stable across runs and builds, unlike `DefaultHasher`, so keys keep their backend
+        // when the load-balancer restarts
+        let hash = hash32(&key) as usize;
+        let index = hash % backends.len();
+        if candidates.contains(&index) {
+            index
+        } else {
+            candidates[hash % candidates.len()]
+        }
+    }
+}
+
+/// Points of the consistent hashing ring, sorted by hash, each one pointing to the index of the
+/// backend owning it. The addresses of the backends the ring was built from are kept to detect
+/// changes to the pool.
+#[derive(Default)]
+struct Ring {
+    points: Vec<(u32, usize)>,
+    addrs: Vec<String>,
+}
+
+impl Ring {
+    /// Build the ring ketama-style: every backend owns `virtual_nodes` points, obtained by
+    /// splitting the MD5 digest of `<addr>-<n>` in four 32 bits hashes.
+    fn new(backends: &[Backend], virtual_nodes: usize) -> Ring {
+        let mut points = Vec::with_capacity(backends.len() * virtual_nodes);
+        for (index, backend) in backends.iter().enumerate() {
+            for n in 0..virtual_nodes.div_ceil(4) {
+                let digest = md5::compute(format!("{}-{}", backend.addr, n));
+                for chunk in digest.0.chunks(4).take(virtual_nodes - n * 4) {
+                    let hash = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                    points.push((hash, index));
+                }
+            }
+        }
+        points.sort_unstable();
+        Ring {
+            points,
+            addrs: backends.iter().map(|b| b.addr.clone()).collect(),
+        }
+    }
+
+    fn matches(&self, backends: &[Backend]) -> bool {
+        self.addrs.len() == backends.len()
+            && self.addrs.iter().zip(backends).all(|(a, b)| *a == b.addr)
+    }
+
+    /// Walk the ring clockwise from the point of `hash`, returning the first candidate met.
+    /// Every backend owns at least a point, so a candidate is always found.
+    fn lookup(&self, hash: u32, candidates: &[usize]) -> usize {
+        let mut selectable = vec![false; self.addrs.len()];
+        for &i in candidates {
+            selectable[i] = true;
+        }
+        let start = self.points.partition_point(|(point, _)| *point < hash);
+        self.points[start..]
+            .iter()
+            .chain(self.points[..start].iter())
+            .map(|(_, index)| *index)
+            .find(|index| selectable[*index])
+            .unwrap_or(candidates[0])
+    }
+}
+
+/// Return a 32 bits hash of `key`, stable across runs and builds
+fn hash32(key: &str) -> u32 {
+    let digest = md5::compute(key);
+    u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// Consistent hashing on a ring of virtual nodes: adding or removing a backend only remaps the
+/// keys landing on its own points, about `1 / n` of the total, while a dead backend has its keys
+/// spread over the next ones on the ring.
+pub struct ConsistentHashing {
+    key: HashKey,
+    virtual_nodes: usize,
+    ring: RwLock<Ring>,
+}
+
+impl ConsistentHashing {
+    /// Create a new ConsistentHashing algorithm hashing the requests on `key`, with
+    /// `virtual_nodes` points on the ring for each backend.
+    pub fn new(key: HashKey, virtual_nodes: usize) -> ConsistentHashing {
+        ConsistentHashing {
+            key,
+            virtual_nodes: virtual_nodes.max(1),
+            ring: RwLock::new(Ring::default()),
+        }
+    }
+}
+
+impl LoadBalancing for ConsistentHashing {
+    /// Find the candidate owning the hash of the request key on the ring. The ring is rebuilt
+    /// the first time and every time the backends change. Without a request all the traffic
+    /// maps to the same backend.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        ctx: Option<&RequestContext<'_>>,
+    ) -> usize {
+        let key = ctx
+            .map(|ctx| request_key(&self.key, ctx))
+            .unwrap_or_default();
+        let hash = hash32(&key);
+        {
+            let ring = self.ring.read().unwrap();
+            if ring.matches(backends) {
+                return ring.lookup(hash, candidates);
+            }
+        }
+        let mut ring = self.ring.write().unwrap();
+        if !ring.matches(backends) {
+            *ring = Ring::new(backends, self.virtual_nodes);
+        }
+        ring.lookup(hash, candidates)
+    }
+}
+
+/// Rendezvous (highest random weight) hashing: every backend gets a score hashing the key
+/// together with its address and the highest scoring available one wins. Needs no state, at
+/// the cost of a hash per backend on each request.
+pub struct RendezvousHashing {
+    key: HashKey,
+}
+
+impl RendezvousHashing {
+    /// Create a new RendezvousHashing algorithm hashing the requests on `key`.
+    pub fn new(key: HashKey) -> RendezvousHashing {
+        RendezvousHashing { key }
+    }
+}
+
+impl LoadBalancing for RendezvousHashing {
+    /// Find the candidate with the highest score for the request key. Without a request all
+    /// the traffic maps to the same backend.
+    fn select(
+        &self,
+        backends: &[Backend],
+        candidates: &[usize],
+        ctx: Option<&RequestContext<'_>>,
+    ) -> usize {
+        let key = ctx
+            .map(|ctx| request_key(&self.key, ctx))
+            .unwrap_or_default();
+        *candidates
+            .iter()
+            .max_by_key(|&&i| hash32(&format!("{}-{}", backends[i].addr, key)))
+            .unwrap()
+    }
+}
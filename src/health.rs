@@ -0,0 +1,549 @@
+/// Active and passive health checks of the backends.
+///
+/// Provides the `HealthCheck` configuration, set for the whole pool and optionally overridden
+/// by each backend, the health state machine of the backends and the workers probing them
+/// concurrently, as well as the outlier detection ejecting the backends failing on live
+/// traffic.
+use crate::backend::{Backend, BackendPool};
+use crate::http::{copy_body, read_message, HeadLimits, HttpMessage, HttpMethod, StatusCode};
+use log::{debug, info};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{self, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
+
+#[derive(Debug)]
+pub enum HealthError {
+    /// The connection to the backend failed
+    Connect(io::Error),
+    /// The probe didn't complete within the timeout
+    Timeout,
+    /// The backend answered with a malformed response or closed the connection
+    Response(io::Error),
+    /// The status code of the response is not one of the expected
+    UnexpectedStatus(StatusCode),
+    /// The body of the response doesn't contain the expected text
+    UnexpectedBody,
+}
+
+impl fmt::Display for HealthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthError::Connect(e) => write!(f, "connection failed: {}", e),
+            HealthError::Timeout => write!(f, "timed out"),
+            HealthError::Response(e) => write!(f, "invalid response: {}", e),
+            HealthError::UnexpectedStatus(s) => write!(f, "unexpected status {}", s.as_u16()),
+            HealthError::UnexpectedBody => write!(f, "unexpected body"),
+        }
+    }
+}
+
+impl Error for HealthError {}
+
+/// Health state of a backend, only `Healthy` backends receive new requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthState {
+    /// Not checked yet
+    Unknown,
+    Healthy,
+    Unhealthy,
+    /// Taken out of the pool on purpose: it completes the requests in flight but doesn't get
+    /// new ones, whatever the outcome of the health checks
+    Draining,
+}
+
+impl HealthState {
+    fn from_u8(state: u8) -> HealthState {
+        match state {
+            1 => HealthState::Healthy,
+            2 => HealthState::Unhealthy,
+            3 => HealthState::Draining,
+            _ => HealthState::Unknown,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            HealthState::Unknown => 0,
+            HealthState::Healthy => 1,
+            HealthState::Unhealthy => 2,
+            HealthState::Draining => 3,
+        }
+    }
+}
+
+impl fmt::Display for HealthState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthState::Unknown => write!(f, "unknown"),
+            HealthState::Healthy => write!(f, "healthy"),
+            HealthState::Unhealthy => write!(f, "unhealthy"),
+            HealthState::Draining => write!(f, "draining"),
+        }
+    }
+}
+
+/// Health of a backend, moved between states by the outcome of the health checks.
+///
+/// A backend with unknown health takes the state of the first check. After that a healthy
+/// backend turns unhealthy only after `fall` consecutive failed checks and an unhealthy one
+/// recovers only after `rise` consecutive successful checks, so that a single lost probe or a
+/// single lucky one don't make a backend flap. The state is read lock-free on every request,
+/// the streaks of checks are only updated by the prober.
+#[derive(Debug)]
+pub struct Health {
+    state: AtomicU8,
+    /// Consecutive successful and failed checks
+    streak: Mutex<(usize, usize)>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            state: AtomicU8::new(HealthState::Unknown.as_u8()),
+            streak: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn state(&self) -> HealthState {
+        HealthState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    /// Force the state, resetting the streaks of checks
+    pub fn set(&self, state: HealthState) {
+        let mut streak = self.streak.lock().unwrap();
+        *streak = (0, 0);
+        self.state.store(state.as_u8(), Ordering::Release);
+    }
+
+    /// Record the outcome of a health check, return the previous and the new state if the
+    /// check made the state change. Draining backends stay draining.
+    pub fn record(
+        &self,
+        success: bool,
+        rise: usize,
+        fall: usize,
+    ) -> Option<(HealthState, HealthState)> {
+        let mut streak = self.streak.lock().unwrap();
+        *streak = if success {
+            (streak.0 + 1, 0)
+        } else {
+            (0, streak.1 + 1)
+        };
+        let state = self.state();
+        let next = match state {
+            HealthState::Unknown if success => HealthState::Healthy,
+            HealthState::Unknown => HealthState::Unhealthy,
+            HealthState::Healthy if streak.1 >= fall.max(1) => HealthState::Unhealthy,
+            HealthState::Unhealthy if streak.0 >= rise.max(1) => HealthState::Healthy,
+            _ => return None,
+        };
+        self.state.store(next.as_u8(), Ordering::Release);
+        Some((state, next))
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new()
+    }
+}
+
+/// Inclusive range of status codes. In the configuration it's either a single code, a range
+/// like `"200-399"` or a class like `"2xx"`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "StatusRangeEntry")]
+pub struct StatusRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl StatusRange {
+    pub fn new(min: u16, max: u16) -> StatusRange {
+        StatusRange { min, max }
+    }
+
+    pub fn contains(&self, status: StatusCode) -> bool {
+        (self.min..=self.max).contains(&status.as_u16())
+    }
+}
+
+/// The forms a status range can be written in the configuration file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusRangeEntry {
+    Code(u16),
+    Range(String),
+}
+
+impl TryFrom<StatusRangeEntry> for StatusRange {
+    type Error = String;
+
+    fn try_from(entry: StatusRangeEntry) -> Result<StatusRange, String> {
+        let invalid =
+            || "invalid status range, expected e.g. `200`, `200-399` or `2xx`".to_string();
+        let range = match entry {
+            StatusRangeEntry::Code(code) => StatusRange::new(code, code),
+            StatusRangeEntry::Range(s) => {
+                let s = s.trim();
+                if let Some((min, max)) = s.split_once('-') {
+                    let min = min.trim().parse().map_err(|_| invalid())?;
+                    let max = max.trim().parse().map_err(|_| invalid())?;
+                    StatusRange::new(min, max)
+                } else if let Some(class) = s.strip_suffix("xx") {
+                    let class: u16 = class.parse().map_err(|_| invalid())?;
+                    StatusRange::new(class * 100, class * 100 + 99)
+                } else {
+                    let code = s.parse().map_err(|_| invalid())?;
+                    StatusRange::new(code, code)
+                }
+            }
+        };
+        if range.min < 100 || range.max > 999 || range.min > range.max {
+            return Err(invalid());
+        }
+        Ok(range)
+    }
+}
+
+/// Configuration of the health checks of a backend.
+///
+/// Without a `path` the check only opens a TCP connection to the backend, otherwise it sends
+/// an HTTP request and the backend is healthy if the response has one of the expected statuses
+/// and, if set, its body contains `body_contains`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HealthCheck {
+    /// Path of the health endpoint, e.g. `/health`
+    pub path: Option<String>,
+    /// Method of the health request
+    pub method: String,
+    /// Status codes of a healthy response
+    pub expected_statuses: Vec<StatusRange>,
+    /// Text the body of a healthy response must contain
+    pub body_contains: Option<String>,
+    /// Time in milliseconds the check must complete within, connection included
+    pub timeout: u64,
+    /// `Host` header of the health request, defaults to the backend address
+    pub host: Option<String>,
+    /// Consecutive successful checks for an unhealthy backend to turn healthy
+    pub rise: usize,
+    /// Consecutive failed checks for a healthy backend to turn unhealthy
+    pub fall: usize,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            path: None,
+            method: "GET".to_string(),
+            expected_statuses: vec![StatusRange::new(200, 299)],
+            body_contains: None,
+            timeout: 2000,
+            host: None,
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+impl HealthCheck {
+    /// Create a check of the health endpoint at `path`, with the default settings
+    pub fn http(path: &str) -> HealthCheck {
+        HealthCheck {
+            path: Some(path.to_string()),
+            ..HealthCheck::default()
+        }
+    }
+
+    /// Check that the configuration is usable
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(path) = &self.path {
+            HttpMethod::from_parts(&self.method, path.clone())?;
+        }
+        if self.expected_statuses.is_empty() {
+            return Err("no expected status for the health check".into());
+        }
+        Ok(())
+    }
+
+    fn expects(&self, status: StatusCode) -> bool {
+        self.expected_statuses.iter().any(|r| r.contains(status))
+    }
+}
+
+/// Configuration of the passive health checks, ejecting from the pool the backends failing on
+/// live traffic: connection errors, timeouts and `5xx` responses.
+///
+/// A backend is ejected after `consecutive_failures` failed requests in a row, or when at
+/// least `failure_percentage` percent of the last `request_volume` requests failed. The first
+/// ejection lasts `base_ejection_time`, every following one doubles, up to
+/// `max_ejection_time`. No more than `max_ejection_percent` percent of the backends are
+/// ejected at the same time.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct OutlierDetection {
+    /// Consecutive failed requests ejecting a backend, 0 disables the check
+    pub consecutive_failures: usize,
+    /// Percentage of failed requests ejecting a backend, 0 disables the check
+    pub failure_percentage: u32,
+    /// Number of requests the failure percentage is computed over
+    pub request_volume: usize,
+    /// Time in milliseconds of the first ejection of a backend
+    pub base_ejection_time: u64,
+    /// Max time in milliseconds of an ejection
+    pub max_ejection_time: u64,
+    /// Max percentage of the backends of the pool ejected at the same time
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        OutlierDetection {
+            consecutive_failures: 5,
+            failure_percentage: 0,
+            request_volume: 100,
+            base_ejection_time: 30000,
+            max_ejection_time: 300000,
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+impl OutlierDetection {
+    /// Check that the configuration is usable
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.failure_percentage > 100 || self.max_ejection_percent > 100 {
+            return Err("outlier detection percentages must be between 0 and 100".into());
+        }
+        if self.failure_percentage > 0 && self.request_volume == 0 {
+            return Err("no request volume for the outlier detection".into());
+        }
+        Ok(())
+    }
+
+    /// Return the duration of the ejection following `ejections` previous ones
+    fn ejection_time(&self, ejections: u32) -> Duration {
+        let time = self
+            .base_ejection_time
+            .saturating_mul(1 << ejections.min(32))
+            .min(self.max_ejection_time.max(self.base_ejection_time));
+        Duration::from_millis(time)
+    }
+}
+
+#[derive(Debug, Default)]
+struct OutlierStats {
+    consecutive_failures: usize,
+    requests: usize,
+    failures: usize,
+    /// Ejections in a row, the longer a backend keeps failing the longer it's ejected
+    ejections: u32,
+}
+
+/// Outcomes of the requests forwarded to a backend, tracked to detect it's failing.
+///
+/// The end of the current ejection is read lock-free on every request, the counters are only
+/// updated once a request completes.
+#[derive(Debug)]
+pub struct Outlier {
+    epoch: Instant,
+    /// End of the ejection in milliseconds since `epoch`, 0 if never ejected
+    ejected_until: AtomicU64,
+    stats: Mutex<OutlierStats>,
+}
+
+impl Outlier {
+    pub fn new() -> Outlier {
+        Outlier {
+            epoch: Instant::now(),
+            ejected_until: AtomicU64::new(0),
+            stats: Mutex::new(OutlierStats::default()),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// Return true if the backend is currently ejected
+    pub fn is_ejected(&self) -> bool {
+        self.now() < self.ejected_until.load(Ordering::Acquire)
+    }
+
+    /// Record the outcome of a request, return true if the backend should be ejected according
+    /// to `config`. Outcomes of requests completing while the backend is ejected are ignored.
+    pub fn record(&self, success: bool, config: &OutlierDetection) -> bool {
+        if self.is_ejected() {
+            return false;
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.requests += 1;
+        if success {
+            stats.consecutive_failures = 0;
+        } else {
+            stats.consecutive_failures += 1;
+            stats.failures += 1;
+        }
+        if config.consecutive_failures > 0
+            && stats.consecutive_failures >= config.consecutive_failures
+        {
+            return true;
+        }
+        if config.failure_percentage > 0 && stats.requests >= config.request_volume {
+            let failing =
+                stats.failures * 100 >= config.failure_percentage as usize * stats.requests;
+            stats.requests = 0;
+            stats.failures = 0;
+            return failing;
+        }
+        false
+    }
+
+    /// Eject the backend, return the duration of the ejection.
+    ///
+    /// The duration doubles with every ejection, the backend is forgiven once it's been serving
+    /// without being ejected for `max_ejection_time` after the end of the last ejection.
+    pub fn eject(&self, config: &OutlierDetection) -> Duration {
+        let mut stats = self.stats.lock().unwrap();
+        let now = self.now();
+        let until = self.ejected_until.load(Ordering::Acquire);
+        if until > 0 && now.saturating_sub(until) >= config.max_ejection_time {
+            stats.ejections = 0;
+        }
+        let duration = config.ejection_time(stats.ejections);
+        stats.ejections += 1;
+        stats.consecutive_failures = 0;
+        stats.requests = 0;
+        stats.failures = 0;
+        self.ejected_until
+            .store(now + duration.as_millis() as u64, Ordering::Release);
+        duration
+    }
+}
+
+impl Default for Outlier {
+    fn default() -> Self {
+        Outlier::new()
+    }
+}
+
+/// Check `backend` once according to its health check configuration and apply the outcome to
+/// its health state, logging the transitions. Return the previous and the new state if it
+/// changed.
+pub async fn probe_backend(
+    backend: &Backend,
+    limits: &HeadLimits,
+) -> Option<(HealthState, HealthState)> {
+    let result = check(&backend.addr, backend.health_check(), limits).await;
+    if let Err(e) = &result {
+        debug!("Health check of {} failed: {}", backend.addr, e);
+    }
+    let transition = backend.record_health_check(result.is_ok());
+    if let Some((from, to)) = transition {
+        info!("Backend {} is now {} (was {})", backend.addr, to, from);
+    }
+    transition
+}
+
+/// Spawn a worker for each backend of the pool, checking it every `interval`.
+///
+/// The backends are checked independently and each check is bounded by its timeout, so a slow
+/// or unreachable backend doesn't delay the checks of the others. The state of the backends is
+/// updated through atomics, so no request is blocked while probing.
+pub fn spawn_probes(pool: Arc<BackendPool>, interval: Duration, limits: HeadLimits) {
+    for index in 0..pool.len() {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            loop {
+                probe_backend(&pool[index], &limits).await;
+                // Sleep for a defined timeout
+                time::delay_for(interval).await;
+            }
+        });
+    }
+}
+
+/// Probe the backend at `addr` according to `check`, the whole probe is bounded by the timeout
+/// of the check.
+///
+/// # Errors
+///
+/// Return an `Err` describing why the backend is not healthy.
+pub async fn check(
+    addr: &str,
+    check: &HealthCheck,
+    limits: &HeadLimits,
+) -> Result<(), HealthError> {
+    let timeout = Duration::from_millis(check.timeout);
+    match time::timeout(timeout, probe(addr, check, limits)).await {
+        Ok(result) => result,
+        Err(_) => Err(HealthError::Timeout),
+    }
+}
+
+async fn probe(addr: &str, check: &HealthCheck, limits: &HeadLimits) -> Result<(), HealthError> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(HealthError::Connect)?;
+    let path = match &check.path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let method = HttpMethod::from_parts(&check.method, path.clone())
+        .map_err(|e| HealthError::Response(e.into()))?;
+    let is_head = matches!(method, HttpMethod::Head(_));
+    let host = check.host.as_deref().unwrap_or(addr);
+    let request = HttpMessage::new(
+        method,
+        [
+            ("Host".to_string(), host.to_string()),
+            ("Connection".to_string(), "close".to_string()),
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    );
+    let mut stream = BufReader::new(stream);
+    stream
+        .write_all(&request.to_bytes())
+        .await
+        .map_err(HealthError::Response)?;
+    let response = match read_message(&mut stream, limits).await {
+        Ok(Some((response, _))) => response,
+        Ok(None) => {
+            return Err(HealthError::Response(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            )))
+        }
+        Err(e) => return Err(HealthError::Response(e)),
+    };
+    let status = response.status_code().ok_or_else(|| {
+        HealthError::Response(io::Error::new(io::ErrorKind::InvalidData, "not a response"))
+    })?;
+    if !check.expects(status) {
+        return Err(HealthError::UnexpectedStatus(status));
+    }
+    if let Some(text) = &check.body_contains {
+        let mut body = Vec::new();
+        if !is_head {
+            let length = response
+                .body_length()
+                .map_err(|e| HealthError::Response(e.into()))?;
+            copy_body(&mut stream, &mut body, length)
+                .await
+                .map_err(HealthError::Response)?;
+        }
+        if !String::from_utf8_lossy(&body).contains(text.as_str()) {
+            return Err(HealthError::UnexpectedBody);
+        }
+    }
+    Ok(())
+}
@@ -1,17 +1,84 @@
 /// HTTP parsing.
 ///
-/// Provides a `parse_message` function to parse incoming requests or responses from
-/// a stream.
-use std::collections::HashMap;
+/// Provides a `parse_head` function to incrementally parse incoming requests or responses
+/// from a stream, plus a couple of async helpers to read a message head and to stream a
+/// message body from a reader to a writer according to its framing.
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::error::Error;
 use std::fmt;
+use std::future::poll_fn;
 use std::hash::Hash;
-
-const CRLF: &str = "\r\n\r\n";
+use std::iter::FromIterator;
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio::io::{self, AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug, PartialEq)]
 pub enum HttpError {
-    ParsingError,
+    /// The buffer doesn't hold a complete message head yet, more bytes are needed
+    Incomplete,
+    /// The message head is malformed
+    Invalid,
+    /// The HTTP version of the message is not supported
+    UnsupportedVersion,
     InvalidStatusCode,
+    /// The message head is bigger than the max size allowed
+    HeadTooLarge,
+    /// The message head carries more headers than allowed
+    TooManyHeaders,
+    /// The message body is bigger than the max size allowed
+    BodyTooLarge,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Incomplete => write!(f, "Incomplete HTTP message"),
+            HttpError::Invalid => write!(f, "Invalid HTTP message"),
+            HttpError::UnsupportedVersion => write!(f, "Unsupported HTTP version"),
+            HttpError::InvalidStatusCode => write!(f, "Invalid HTTP status code"),
+            HttpError::HeadTooLarge => write!(f, "HTTP message head too large"),
+            HttpError::TooManyHeaders => write!(f, "Too many HTTP headers"),
+            HttpError::BodyTooLarge => write!(f, "HTTP message body too large"),
+        }
+    }
+}
+
+impl Error for HttpError {}
+
+impl From<HttpError> for io::Error {
+    fn from(e: HttpError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl HttpError {
+    /// Return the `HttpError` wrapped by an `io::Error`, if any, e.g. the parsing error that
+    /// made `read_message` fail.
+    pub fn from_io(e: &io::Error) -> Option<&HttpError> {
+        e.get_ref().and_then(|e| e.downcast_ref::<HttpError>())
+    }
+}
+
+/// Limits enforced while parsing the head of a message, to avoid buffering an unbounded
+/// amount of data for a single request.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HeadLimits {
+    /// Max size in bytes of the start line and headers
+    pub max_size: usize,
+    /// Max number of headers
+    pub max_headers: usize,
+}
+
+impl Default for HeadLimits {
+    fn default() -> HeadLimits {
+        HeadLimits {
+            max_size: 16384,
+            max_headers: 100,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Copy)]
@@ -29,33 +96,99 @@ impl fmt::Display for HttpVersion {
     }
 }
 
-impl HttpVersion {
-    pub fn from_str(s: &str) -> HttpVersion {
-        if s.starts_with("HTTP/1.0") {
-            HttpVersion::V10
-        } else if s.starts_with("HTTP/1.1") {
-            HttpVersion::V11
-        } else {
-            panic!("Unsupported HTTP version")
+impl FromStr for HttpVersion {
+    type Err = HttpError;
+
+    fn from_str(s: &str) -> Result<HttpVersion, HttpError> {
+        match s {
+            "HTTP/1.0" => Ok(HttpVersion::V10),
+            "HTTP/1.1" => Ok(HttpVersion::V11),
+            _ if s.starts_with("HTTP/") => Err(HttpError::UnsupportedVersion),
+            _ => Err(HttpError::Invalid),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct StatusCode(u16);
 
 impl StatusCode {
+    pub const CONTINUE: StatusCode = StatusCode(100);
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const BAD_GATEWAY: StatusCode = StatusCode(502);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const GATEWAY_TIMEOUT: StatusCode = StatusCode(504);
+    pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode = StatusCode(505);
+
     pub fn new(code: u16) -> StatusCode {
         StatusCode(code)
     }
 
+    /// Return the numeric value of the status code
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// Return `true` for the informational `1xx` statuses announcing that the final response
+    /// is still to come, that is all of them but `101 Switching Protocols`
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.0) && *self != StatusCode::SWITCHING_PROTOCOLS
+    }
+
+    /// Return the reason phrase of the status code as defined by RFC 7231, or an empty string
+    /// for the codes not defined there
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            414 => "URI Too Long",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+}
+
+impl FromStr for StatusCode {
+    type Err = HttpError;
+
     /// Parse status code from the first 3 bytes of the header string.
     ///
     /// # Errors
     ///
     /// Return an `Err` in case of a header line below 3 bytes length or if the code result non
     /// valid (e.g below 100 or over 599, according to the HTTP status codes)
-    pub fn from_str(str: &String) -> Result<StatusCode, HttpError> {
+    fn from_str(str: &str) -> Result<StatusCode, HttpError> {
         let bytes = str.as_bytes();
         if bytes.len() < 3 {
             return Err(HttpError::InvalidStatusCode);
@@ -74,27 +207,107 @@ impl StatusCode {
     }
 }
 
+/// Method of a request along with its route.
+///
+/// Methods not defined by RFC 7231 and RFC 5789, e.g. WebDAV ones like `PROPFIND`, are carried
+/// as they are by the `Extension` variant, holding the method name and the route.
 #[derive(Debug, Clone, PartialEq, Hash)]
 pub enum HttpMethod {
     Get(String),
+    Head(String),
     Post(String),
     Put(String),
     Delete(String),
     Connect(String),
-    Head,
+    Options(String),
+    Trace(String),
+    Patch(String),
+    Extension(String, String),
 }
 
-impl fmt::Display for HttpMethod {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl HttpMethod {
+    /// Create a method from its name and the route of the request.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err(HttpError::Invalid)` if the name is not a valid token according to RFC
+    /// 7230.
+    pub fn from_parts(name: &str, route: String) -> Result<HttpMethod, HttpError> {
+        let method = match name {
+            "GET" => HttpMethod::Get(route),
+            "HEAD" => HttpMethod::Head(route),
+            "POST" => HttpMethod::Post(route),
+            "PUT" => HttpMethod::Put(route),
+            "DELETE" => HttpMethod::Delete(route),
+            "CONNECT" => HttpMethod::Connect(route),
+            "OPTIONS" => HttpMethod::Options(route),
+            "TRACE" => HttpMethod::Trace(route),
+            "PATCH" => HttpMethod::Patch(route),
+            _ if is_token(name) => HttpMethod::Extension(name.to_string(), route),
+            _ => return Err(HttpError::Invalid),
+        };
+        Ok(method)
+    }
+
+    /// Return the name of the method, e.g. `GET`
+    pub fn name(&self) -> &str {
+        match self {
+            HttpMethod::Get(_) => "GET",
+            HttpMethod::Head(_) => "HEAD",
+            HttpMethod::Post(_) => "POST",
+            HttpMethod::Put(_) => "PUT",
+            HttpMethod::Delete(_) => "DELETE",
+            HttpMethod::Connect(_) => "CONNECT",
+            HttpMethod::Options(_) => "OPTIONS",
+            HttpMethod::Trace(_) => "TRACE",
+            HttpMethod::Patch(_) => "PATCH",
+            HttpMethod::Extension(name, _) => name,
+        }
+    }
+
+    /// Return the route of the request
+    pub fn route(&self) -> &String {
         match self {
-            HttpMethod::Get(r) => write!(f, "GET {}", r),
-            HttpMethod::Post(r) => write!(f, "POST {}", r),
-            HttpMethod::Head => write!(f, "HEAD"),
-            HttpMethod::Put(r) => write!(f, "PUT {}", r),
-            HttpMethod::Delete(r) => write!(f, "DELETE {}", r),
-            HttpMethod::Connect(r) => write!(f, "CONNECT {}", r),
+            HttpMethod::Get(r)
+            | HttpMethod::Head(r)
+            | HttpMethod::Post(r)
+            | HttpMethod::Put(r)
+            | HttpMethod::Delete(r)
+            | HttpMethod::Connect(r)
+            | HttpMethod::Options(r)
+            | HttpMethod::Trace(r)
+            | HttpMethod::Patch(r)
+            | HttpMethod::Extension(_, r) => r,
         }
     }
+
+    /// Return `true` if sending the request several times has the same effect as sending it
+    /// once, as defined by RFC 7231, so it can be safely retried
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            HttpMethod::Get(_)
+                | HttpMethod::Head(_)
+                | HttpMethod::Put(_)
+                | HttpMethod::Delete(_)
+                | HttpMethod::Options(_)
+                | HttpMethod::Trace(_)
+        )
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name(), self.route())
+    }
+}
+
+/// Return `true` if `s` is a token as defined by RFC 7230, the syntax of method and header
+/// names
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -107,19 +320,131 @@ impl fmt::Display for HttpHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             HttpHeader::Method(v, m) => write!(f, "{} {}", m, v),
-            HttpHeader::Status(v, s) => write!(f, "{} {}", s, v),
+            HttpHeader::Status(v, s) => write!(f, "{} {}", v, s),
+        }
+    }
+}
+
+/// Framing of an HTTP message body, following the rules of RFC 7230 section 3.3.3.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyLength {
+    /// No body follows the head
+    Empty,
+    /// Exactly `n` bytes follow the head, as declared by `Content-Length`
+    Fixed(u64),
+    /// The body is a sequence of chunks, as declared by `Transfer-Encoding: chunked`
+    Chunked,
+    /// The body ends when the peer closes the connection
+    UntilClose,
+}
+
+/// Collection of the headers of a message.
+///
+/// Names are looked up ignoring case, while the order and the casing the headers were received
+/// with are preserved when the message is serialised. A name can be associated to multiple
+/// values, e.g. `Set-Cookie`, each one stored as a separate entry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return the first value of the header `name` or `None` if the header is not present.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Return all the values of the header `name`, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Return `true` if `token` appears in the comma separated list of values of the header
+    /// `name`, e.g. `close` in `Connection: keep-alive, close`. Comparison ignores case.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Add a value for the header `name`, keeping the values already present.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Set the value of the header `name`, replacing all the values already present. The header
+    /// keeps the position of its first occurrence, if any.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self
+            .entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(name))
+        {
+            Some(i) => {
+                self.entries[i] = (name.to_string(), value.to_string());
+                let tail = self.entries.split_off(i + 1);
+                self.entries.extend(
+                    tail.into_iter()
+                        .filter(|(k, _)| !k.eq_ignore_ascii_case(name)),
+                );
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    /// Remove all the values of the header `name`, returning `true` if any was present.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        self.entries.len() != len
+    }
+
+    /// Iterate over the headers as `(name, value)` pairs, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+impl FromIterator<(String, String)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Headers {
+        Headers {
+            entries: iter.into_iter().collect(),
         }
     }
 }
 
 pub struct HttpMessage {
     pub header: HttpHeader,
-    pub headers: HashMap<String, String>,
-    pub body: Option<String>,
+    pub headers: Headers,
+    pub body: Option<Vec<u8>>,
 }
 
 impl HttpMessage {
-    pub fn new(method: HttpMethod, headers: HashMap<String, String>) -> HttpMessage {
+    pub fn new(method: HttpMethod, headers: Headers) -> HttpMessage {
         HttpMessage {
             header: HttpHeader::Method(HttpVersion::V11, method),
             headers,
@@ -127,6 +452,18 @@ impl HttpMessage {
         }
     }
 
+    /// Create a response with `status`, without a body
+    pub fn response(status: StatusCode, headers: Headers) -> HttpMessage {
+        HttpMessage {
+            header: HttpHeader::Status(
+                HttpVersion::V11,
+                format!("{} {}", status.as_u16(), status.reason()),
+            ),
+            headers,
+            body: None,
+        }
+    }
+
     /// Return the method of the request or `None` if it's an HTTP response
     pub fn method(&self) -> Option<&HttpMethod> {
         match &self.header {
@@ -143,111 +480,634 @@ impl HttpMessage {
         }
     }
 
-    /// Return the `Transfer-Encoding` value of the response or `None` if it's an HTTP response or
-    /// the value is not found.
-    pub fn transfer_encoding(&self) -> Option<&String> {
-        self.headers.get("Transfer-Encoding")
+    /// Return the first value of the header `name`, looked up ignoring case, or `None` if the
+    /// header is not present.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
     }
 
-    /// Return the route of the request or `None` if it's a response or an unknown request type.
-    pub fn route(&self) -> Option<&String> {
-        match self.method() {
-            Some(method) => match method {
-                HttpMethod::Get(route)
-                | HttpMethod::Post(route)
-                | HttpMethod::Put(route)
-                | HttpMethod::Connect(route)
-                | HttpMethod::Delete(route) => Some(route),
-                _ => None,
-            },
-            _ => None,
+    /// Return the `Transfer-Encoding` value of the message or `None` if the value is not found.
+    pub fn transfer_encoding(&self) -> Option<&str> {
+        self.header("Transfer-Encoding")
+    }
+
+    /// Return the `Content-Length` value of the message or `None` if the header is not present.
+    /// The header can be repeated, or hold a list of values, as long as they are all the same.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err(HttpError::Invalid)` if a value is not made of digits only or if the
+    /// values differ, as the end of the body can't be told for sure.
+    pub fn content_length(&self) -> Result<Option<u64>, HttpError> {
+        let mut length = None;
+        for value in self
+            .headers
+            .get_all("Content-Length")
+            .flat_map(|v| v.split(','))
+        {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(HttpError::Invalid);
+            }
+            let n = value.parse().map_err(|_| HttpError::Invalid)?;
+            if length.is_some_and(|length| length != n) {
+                return Err(HttpError::Invalid);
+            }
+            length = Some(n);
+        }
+        Ok(length)
+    }
+
+    /// Return `true` if the body of the message is sent in chunked mode, the last encoding
+    /// applied, across all the `Transfer-Encoding` headers, is the one that matters.
+    pub fn is_chunked(&self) -> bool {
+        self.headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|te| te.split(','))
+            .last()
+            .is_some_and(|te| te.trim().eq_ignore_ascii_case("chunked"))
+    }
+
+    /// Return the framing of the body following the head of the message.
+    ///
+    /// Responses with a status code that never carries a body (1xx, 204 and 304) have none,
+    /// whatever their headers say. `Transfer-Encoding` takes precedence over `Content-Length`,
+    /// which must then be dropped before forwarding the message. Requests without
+    /// `Content-Length` or `Transfer-Encoding` have no body, responses without them are read
+    /// until the connection is closed, as are responses whose last transfer coding is not
+    /// `chunked`. Responses to `HEAD` requests have no body either but that must be handled by
+    /// the caller, as it depends on the request.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err(HttpError::Invalid)` for requests whose last transfer coding is not
+    /// `chunked` and for messages with an invalid `Content-Length`, see `content_length`.
+    /// Such messages must be rejected, guessing where they end would let a peer disagreeing
+    /// on it smuggle a message inside the body of another one.
+    pub fn body_length(&self) -> Result<BodyLength, HttpError> {
+        let status = self.status_code().map(|s| s.as_u16());
+        if let Some(100..=199) | Some(204) | Some(304) = status {
+            return Ok(BodyLength::Empty);
+        }
+        if self.headers.contains_key("Transfer-Encoding") {
+            return match (self.is_chunked(), status) {
+                (true, _) => Ok(BodyLength::Chunked),
+                (false, Some(_)) => Ok(BodyLength::UntilClose),
+                (false, None) => Err(HttpError::Invalid),
+            };
         }
+        match (self.content_length()?, status) {
+            (Some(n), _) => Ok(BodyLength::Fixed(n)),
+            (None, None) => Ok(BodyLength::Empty),
+            (None, Some(_)) => Ok(BodyLength::UntilClose),
+        }
+    }
+
+    /// Return `true` if the connection the message travels on can be reused for further
+    /// messages, according to the `Connection` header and the default of the HTTP version:
+    /// HTTP/1.1 connections are persistent unless `close` is requested, HTTP/1.0 ones only
+    /// when `keep-alive` is requested.
+    pub fn keep_alive(&self) -> bool {
+        match self.http_version() {
+            Some(HttpVersion::V11) => !self.headers.has_token("Connection", "close"),
+            _ => self.headers.has_token("Connection", "keep-alive"),
+        }
+    }
+
+    /// Return the route of the request or `None` if it's a response.
+    pub fn route(&self) -> Option<&String> {
+        self.method().map(|m| m.route())
     }
 
     /// Return the status code of the response or `None` if it's a request.
     pub fn status_code(&self) -> Option<StatusCode> {
         match &self.header {
-            HttpHeader::Status(_, s) => match StatusCode::from_str(&s) {
-                Ok(r) => Some(r),
-                Err(_) => None,
-            },
+            HttpHeader::Status(_, s) => StatusCode::from_str(s).ok(),
             _ => None,
         }
     }
+
+    /// Return the head of the message, start line and headers up to the empty line separating
+    /// them from the body. Use `head_bytes` to obtain the exact representation.
+    pub fn head(&self) -> String {
+        let mut head = format!("{}\r\n", self.header);
+        for (k, v) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    /// Serialise the head of the message as it's sent on the wire. Characters up to U+00FF are
+    /// written as single bytes, so that the bytes of a parsed head are sent back unchanged, the
+    /// others are encoded in UTF-8.
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for c in self.head().chars() {
+            match u8::try_from(c) {
+                Ok(b) => bytes.push(b),
+                Err(_) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        bytes
+    }
+
+    /// Serialise the message as it's sent on the wire, the body is copied byte-for-byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.head_bytes();
+        if let Some(body) = &self.body {
+            bytes.extend_from_slice(body);
+        }
+        bytes
+    }
 }
 
 impl fmt::Display for HttpMessage {
+    /// Format the message as text, bytes of the body that are not valid UTF-8 are replaced, use
+    /// `to_bytes` to obtain the exact representation.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut headers_str = String::new();
-        for (k, v) in self.headers.iter() {
-            headers_str.push_str(&format!("{}: {}\r\n", k, v));
-        }
         let body = match &self.body {
-            Some(b) => b,
-            None => "",
+            Some(b) => String::from_utf8_lossy(b),
+            None => "".into(),
         };
-        let repr = format!("{}\r\n{}{}{}", self.header, &headers_str, body, CRLF);
-        write!(f, "{}", repr)
+        write!(f, "{}{}", self.head(), body)
     }
 }
 
-/// Parse an HTTP message
+/// Parse the head of an HTTP message, that is the start line and the headers up to the empty
+/// line separating them from the body.
 ///
-/// Receive a buffer argument representing a bytearray received from an
-/// open stream.
+/// The buffer can hold a partial message, in which case `Err(HttpError::Incomplete)` is returned
+/// and the parsing can be retried once more bytes are received. Empty lines preceding the start
+/// line are ignored, but count in the size of the head. On success return the message along
+/// with the number of bytes of the buffer the head spans, body bytes following it are not
+/// consumed.
 ///
 /// # Errors
 ///
-/// Return an `Err(HttpError::ParsingError)` in case of an error parsing the header of the request,
-/// this can happen for example if an unknown method appears on the header line.
+/// Return an `Err(HttpError::Invalid)` in case of a malformed start line or header line, e.g.
+/// a method which is not a token, a header without a colon or with control characters in its
+/// value,
+/// `Err(HttpError::UnsupportedVersion)` for versions other than HTTP/1.0 and HTTP/1.1,
+/// `Err(HttpError::InvalidStatusCode)` for responses with a malformed status code and
+/// `Err(HttpError::HeadTooLarge)` or `Err(HttpError::TooManyHeaders)` if the head exceeds the
+/// limits.
+pub fn parse_head(buffer: &[u8], limits: &HeadLimits) -> Result<(HttpMessage, usize), HttpError> {
+    let start = buffer
+        .iter()
+        .take_while(|&&b| b == b'\r' || b == b'\n')
+        .count();
+    let (end, len) = match find_head_end(&buffer[start..]) {
+        Some((end, len)) if start + end <= limits.max_size => (start + end, start + end + len),
+        Some(_) => return Err(HttpError::HeadTooLarge),
+        None if buffer.len() > limits.max_size => return Err(HttpError::HeadTooLarge),
+        None => return Err(HttpError::Incomplete),
+    };
+    // Decoded as Latin-1 rather than UTF-8, so that no byte is lost and `head_bytes` gives back
+    // the obs-text bytes of header values as they were received
+    let head: String = buffer[start..end].iter().map(|&b| char::from(b)).collect();
+    let mut lines = head.lines();
+    let header = parse_start_line(lines.next().unwrap_or(""))?;
+
+    // Populate headers map, the first line containing just the HTTP method and route or the
+    // status has already been consumed
+    let mut headers = Headers::new();
+    for line in lines {
+        if headers.len() == limits.max_headers {
+            return Err(HttpError::TooManyHeaders);
+        }
+        let (name, value) = parse_header_line(line)?;
+        headers.append(name, value);
+    }
+    Ok((
+        HttpMessage {
+            header,
+            headers,
+            body: None,
+        },
+        len,
+    ))
+}
+
+/// Parse a whole HTTP message held by the buffer, anything following the head is taken as the
+/// body, default `HeadLimits` are enforced.
 ///
-/// # Panics
+/// # Errors
 ///
-/// The `parse_header` function will panic in case of missing mandatory fields
-/// like HTTP version, a supported valid method
+/// Return the same errors as `parse_head`.
 pub fn parse_message(buffer: &[u8]) -> Result<HttpMessage, HttpError> {
-    let request_str = String::from_utf8_lossy(&buffer[..]);
-    let content: Vec<&str> = request_str.split(CRLF).collect();
-    let first_line: Vec<&str> = content[0].split_whitespace().collect();
-
-    // Not really solid but separate version and route based on the start of the header line:
-    //
-    // - If the first line starts with HTTP it's an HTTP response so the HTTP version is the first
-    // token we must extract and no route are provided;
-    // - Otherwise the version is generally the third token ot be parsed, following the route one
-    let (version, route) = if content[0].starts_with("HTTP") {
-        (HttpVersion::from_str(&content[0]), None)
-    } else {
-        (
-            HttpVersion::from_str(&first_line[2]),
-            Some(first_line[1].to_string()),
-        )
-    };
+    let (mut message, len) = parse_head(buffer, &HeadLimits::default())?;
+    message.body = Some(buffer[len..].to_vec());
+    Ok(message)
+}
+
+/// Find the empty line terminating the head, returning the index where it starts and its length
+fn find_head_end(buffer: &[u8]) -> Option<(usize, usize)> {
+    buffer.iter().enumerate().find_map(|(i, &b)| {
+        if b != b'\n' {
+            None
+        } else if buffer[i + 1..].starts_with(b"\r\n") {
+            Some((i + 1, 2))
+        } else if buffer[i + 1..].starts_with(b"\n") {
+            Some((i + 1, 1))
+        } else {
+            None
+        }
+    })
+}
 
+fn parse_start_line(line: &str) -> Result<HttpHeader, HttpError> {
+    // If the first line starts with HTTP it's an HTTP response so the HTTP version is the first
+    // token, followed by the status code and an optional reason phrase; otherwise it's a
+    // request made of exactly three tokens: method, route and version
+    if line.starts_with("HTTP/") {
+        let mut tokens = line.splitn(2, ' ');
+        let version = HttpVersion::from_str(tokens.next().unwrap_or(""))?;
+        let status = tokens.next().unwrap_or("").trim();
+        let code = status.split(' ').next().unwrap_or("");
+        if code.len() != 3 {
+            return Err(HttpError::InvalidStatusCode);
+        }
+        StatusCode::from_str(code)?;
+        return Ok(HttpHeader::Status(version, status.to_string()));
+    }
+    let tokens: Vec<&str> = line.split(' ').collect();
+    if tokens.len() != 3 || tokens[1].is_empty() {
+        return Err(HttpError::Invalid);
+    }
+    let version = HttpVersion::from_str(tokens[2])?;
     // Parse the method (verb of the request)
-    let headline = match first_line[0] {
-        "GET" => HttpHeader::Method(version, HttpMethod::Get(route.unwrap())),
-        "POST" => HttpHeader::Method(version, HttpMethod::Post(route.unwrap())),
-        "PUT" => HttpHeader::Method(version, HttpMethod::Put(route.unwrap())),
-        "DELETE" => HttpHeader::Method(version, HttpMethod::Delete(route.unwrap())),
-        "CONNECT" => HttpHeader::Method(version, HttpMethod::Connect(route.unwrap())),
-        "HEAD" => HttpHeader::Method(version, HttpMethod::Head),
-        _ => HttpHeader::Status(version, first_line[1].to_string()),
-    };
+    let method = HttpMethod::from_parts(tokens[0], tokens[1].to_string())?;
+    Ok(HttpHeader::Method(version, method))
+}
+
+/// Split a header line into name and value, the value is stripped of the surrounding
+/// whitespaces.
+fn parse_header_line(line: &str) -> Result<(&str, &str), HttpError> {
+    let mut parts = line.splitn(2, ':');
+    let name = parts.next().unwrap_or("");
+    let value = parts.next().ok_or(HttpError::Invalid)?;
+    // Header names are tokens, whitespaces are not allowed, this also rejects obsolete line
+    // folding (continuation lines starting with a whitespace)
+    if !is_token(name) {
+        return Err(HttpError::Invalid);
+    }
+    // Values can't hold control characters other than tabs, e.g. a bare CR or a NUL, which
+    // other parsers may read as the end of the line
+    if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+        return Err(HttpError::Invalid);
+    }
+    Ok((name, value.trim()))
+}
+
+/// Read the head of an HTTP message from the stream and parse it, feeding the incremental
+/// parser with the bytes as they arrive. Only the bytes of the head are consumed from the
+/// reader, the body is left to be read by the caller.
+///
+/// Return the message along with the raw bytes of its head, or `None` if the reader reached
+/// the EOF before any byte of the message was received.
+///
+/// # Errors
+///
+/// Return an `Err` with `UnexpectedEof` kind if the stream ends in the middle of the head, an
+/// `Err` with `InvalidData` kind wrapping the `HttpError` if the head is not valid (see
+/// `HttpError::from_io`), or any error raised by the underlying reader.
+pub async fn read_message<R>(
+    reader: &mut R,
+    limits: &HeadLimits,
+) -> io::Result<Option<(HttpMessage, Vec<u8>)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = Vec::new();
+    loop {
+        let consumed = head.len();
+        if fill_buf(reader, &mut head).await? == 0 {
+            if head.iter().all(|&b| b == b'\r' || b == b'\n') {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match parse_head(&head, limits) {
+            Ok((message, len)) => {
+                Pin::new(&mut *reader).consume(len - consumed);
+                head.truncate(len);
+                // Leading empty lines are not part of the message
+                let start = head
+                    .iter()
+                    .take_while(|&&b| b == b'\r' || b == b'\n')
+                    .count();
+                head.drain(..start);
+                return Ok(Some((message, head)));
+            }
+            Err(HttpError::Incomplete) => Pin::new(&mut *reader).consume(head.len() - consumed),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Stream the body of an HTTP message from `reader` to `writer` according to its framing,
+/// without buffering it as a whole. Chunked bodies are forwarded untouched, chunk sizes,
+/// extensions and trailers included, once validated by a `ChunkedDecoder`.
+///
+/// Return the number of bytes written.
+///
+/// # Errors
+///
+/// Return an `Err` with `UnexpectedEof` kind if the stream ends before the body is complete,
+/// `InvalidData` kind in case of malformed chunks, or any error raised by the underlying reader
+/// and writer.
+pub async fn copy_body<R, W>(reader: &mut R, writer: &mut W, length: BodyLength) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        BodyLength::Empty => Ok(0),
+        BodyLength::Fixed(n) => copy_exact(reader, writer, n).await,
+        BodyLength::UntilClose => io::copy(reader, writer).await,
+        BodyLength::Chunked => copy_chunked(reader, writer, None).await,
+    }
+}
+
+/// Stream the body of an HTTP message like `copy_body`, failing once its content exceeds `max`
+/// bytes. The limit applies to the chunk data of chunked bodies, not to their framing, and
+/// is checked as the chunks are received, before the body is complete.
+///
+/// Return the number of bytes written.
+///
+/// # Errors
+///
+/// Return an `Err` with `InvalidData` kind wrapping `HttpError::BodyTooLarge` if the body is
+/// larger than `max`, or any error `copy_body` returns. Part of the body may have been written
+/// already when the limit is hit.
+pub async fn copy_body_limited<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: BodyLength,
+    max: u64,
+) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        BodyLength::Fixed(n) if n > max => Err(HttpError::BodyTooLarge.into()),
+        BodyLength::UntilClose => {
+            let copied = io::copy(&mut (&mut *reader).take(max + 1), writer).await?;
+            if copied > max {
+                return Err(HttpError::BodyTooLarge.into());
+            }
+            Ok(copied)
+        }
+        BodyLength::Chunked => copy_chunked(reader, writer, Some(max)).await,
+        _ => copy_body(reader, writer, length).await,
+    }
+}
+
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, n: u64) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = io::copy(&mut (&mut *reader).take(n), writer).await?;
+    if copied < n {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(copied)
+}
+
+/// Stream a chunked body through, validating its framing with a `ChunkedDecoder` to find where
+/// it ends, the bytes are written as they are received. Fail once the chunk data exceeds `max`
+/// bytes if set.
+async fn copy_chunked<R, W>(reader: &mut R, writer: &mut W, max: Option<u64>) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut decoder = ChunkedDecoder::new();
+    let mut buffer = Vec::new();
+    let mut total = 0;
+    let mut data = 0;
+    while !decoder.is_done() {
+        buffer.clear();
+        if fill_buf(reader, &mut buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut consumed = 0;
+        while consumed < buffer.len() && !decoder.is_done() {
+            let (n, chunk) = decoder.decode(&buffer[consumed..])?;
+            consumed += n;
+            data += chunk.len() as u64;
+        }
+        if max.is_some_and(|max| data > max) {
+            return Err(HttpError::BodyTooLarge.into());
+        }
+        writer.write_all(&buffer[..consumed]).await?;
+        Pin::new(&mut *reader).consume(consumed);
+        total += consumed as u64;
+    }
+    Ok(total)
+}
+
+/// Read the whole content of `reader` up to the EOF and write it to `writer` encoded in chunks,
+/// one for every read, followed by the last chunk. Used to frame bodies of unknown length.
+///
+/// Return the number of bytes written, framing included.
+///
+/// # Errors
+///
+/// Return any error raised by the underlying reader and writer.
+pub async fn copy_chunk_encoded<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = Vec::new();
+    let mut total = 0;
+    loop {
+        buffer.clear();
+        if fill_buf(reader, &mut buffer).await? == 0 {
+            let last = encode_last_chunk(&Headers::new());
+            writer.write_all(&last).await?;
+            return Ok(total + last.len() as u64);
+        }
+        let chunk = encode_chunk(&buffer);
+        writer.write_all(&chunk).await?;
+        Pin::new(&mut *reader).consume(buffer.len());
+        total += chunk.len() as u64;
+    }
+}
 
-    // Populate headers map, starting from 1 as index to skip the first line which
-    // contains just the HTTP method and route
-    let headers: HashMap<String, String> = content[0]
-        .split("\r\n")
-        .skip(1)
-        .map(|x| x.splitn(2, ":"))
-        .map(|mut x| (x.next().unwrap().to_string(), x.next().unwrap().to_string()))
-        .collect();
-
-    let body = content[1].trim_end_matches(char::from(0)).to_string();
-    Ok(HttpMessage {
-        header: headline,
-        headers,
-        body: Some(body),
+/// Append the bytes buffered by the reader to `buffer`, reading more from the underlying
+/// stream if none are buffered, without consuming them. Return the number of bytes appended,
+/// 0 meaning EOF.
+async fn fill_buf<R>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    poll_fn(|cx| {
+        Pin::new(&mut *reader)
+            .poll_fill_buf(cx)
+            .map_ok(|available| {
+                buffer.extend_from_slice(available);
+                available.len()
+            })
     })
+    .await
+}
+
+// Max length of a chunk size line or trailer line, extensions included
+const MAX_CHUNK_LINE: usize = 4096;
+
+// Max number of trailers following the last chunk
+const MAX_TRAILERS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkedState {
+    /// Reading the size line of a chunk
+    Size,
+    /// Reading the data of a chunk, holding the bytes left
+    Data(u64),
+    /// Reading the CRLF closing the data of a chunk
+    DataEnd,
+    /// Reading the trailer lines following the last chunk
+    Trailers,
+    Done,
+}
+
+/// Incremental decoder of `Transfer-Encoding: chunked` bodies.
+///
+/// Every chunk is made of a size line in hex, optionally followed by extensions, then the data
+/// and a closing CRLF. A size of 0 marks the last chunk, followed by optional trailers and an
+/// empty line. The decoder can be fed partial buffers as they are received, lines split
+/// across buffers are kept internally until complete.
+#[derive(Debug)]
+pub struct ChunkedDecoder {
+    state: ChunkedState,
+    line: Vec<u8>,
+    trailers: Headers,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> ChunkedDecoder {
+        ChunkedDecoder::new()
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: ChunkedState::Size,
+            line: Vec::new(),
+            trailers: Headers::new(),
+        }
+    }
+
+    /// Return `true` once the whole body, trailers included, has been decoded
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkedState::Done
+    }
+
+    /// Return the trailers received after the last chunk
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
+
+    /// Decode the next piece of `input`, which can be a whole or partial line of framing or
+    /// chunk data.
+    ///
+    /// Return the number of bytes of `input` consumed, at least one unless the input is empty
+    /// or the body is done, and the chunk data found in them, a sub-slice of `input` possibly
+    /// empty. Call it again with the bytes not consumed to proceed.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err(HttpError::Invalid)` in case of malformed chunk size, missing CRLF after
+    /// the chunk data, or malformed trailers, `Err(HttpError::HeadTooLarge)` or
+    /// `Err(HttpError::TooManyHeaders)` if a line is too long or trailers are too many.
+    pub fn decode<'a>(&mut self, input: &'a [u8]) -> Result<(usize, &'a [u8]), HttpError> {
+        if input.is_empty() {
+            return Ok((0, &[]));
+        }
+        match self.state {
+            ChunkedState::Data(left) => {
+                let n = left.min(input.len() as u64) as usize;
+                self.state = if n as u64 == left {
+                    ChunkedState::DataEnd
+                } else {
+                    ChunkedState::Data(left - n as u64)
+                };
+                Ok((n, &input[..n]))
+            }
+            ChunkedState::Done => Ok((0, &[])),
+            _ => {
+                let (n, complete) = match input.iter().position(|&b| b == b'\n') {
+                    Some(i) => (i + 1, true),
+                    None => (input.len(), false),
+                };
+                if self.line.len() + n > MAX_CHUNK_LINE {
+                    return Err(HttpError::HeadTooLarge);
+                }
+                self.line.extend_from_slice(&input[..n]);
+                if complete {
+                    let line = std::mem::take(&mut self.line);
+                    self.decode_line(&line)?;
+                }
+                Ok((n, &[]))
+            }
+        }
+    }
+
+    fn decode_line(&mut self, line: &[u8]) -> Result<(), HttpError> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+        self.state = match self.state {
+            ChunkedState::Size => match parse_chunk_size(line)? {
+                0 => ChunkedState::Trailers,
+                size => ChunkedState::Data(size),
+            },
+            ChunkedState::DataEnd if line.is_empty() => ChunkedState::Size,
+            ChunkedState::DataEnd => return Err(HttpError::Invalid),
+            ChunkedState::Trailers if line.is_empty() => ChunkedState::Done,
+            ChunkedState::Trailers => {
+                if self.trailers.len() == MAX_TRAILERS {
+                    return Err(HttpError::TooManyHeaders);
+                }
+                let (name, value) = parse_header_line(line)?;
+                self.trailers.append(name, value);
+                ChunkedState::Trailers
+            }
+            state => state,
+        };
+        Ok(())
+    }
+}
+
+/// Parse the size of a chunk, in hex, ignoring the extensions following it
+fn parse_chunk_size(line: &str) -> Result<u64, HttpError> {
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(HttpError::Invalid);
+    }
+    u64::from_str_radix(size, 16).map_err(|_| HttpError::Invalid)
+}
+
+/// Encode `data` as a single chunk, data must not be empty as the empty chunk marks the end of
+/// the body, see `encode_last_chunk`.
+pub fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+/// Encode the last chunk terminating a chunked body, followed by the trailers, if any.
+pub fn encode_last_chunk(trailers: &Headers) -> Vec<u8> {
+    let mut chunk = String::from("0\r\n");
+    for (k, v) in trailers.iter() {
+        chunk.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    chunk.push_str("\r\n");
+    chunk.into_bytes()
 }
@@ -1,17 +1,20 @@
 pub mod backend;
 pub mod balancing;
+pub mod health;
 pub mod http;
+pub mod retry;
+pub mod routing;
 pub mod server;
 use chrono::Local;
-use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use log::{LevelFilter, Metadata, Record, SetLoggerError};
 use serde::Deserialize;
-use serde_yaml;
+use std::collections::BTreeMap;
 
 struct SimpleLogger;
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
@@ -29,34 +32,107 @@ impl log::Log for SimpleLogger {
 
 static LOGGER: SimpleLogger = SimpleLogger;
 
-pub fn init_logging() -> Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Info))
+/// Install the logger, printing records up to `level` on the standard output
+pub fn init_logging(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER).map(|()| log::set_max_level(level))
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct Config {
     listen_on: String,
-    backends: Vec<String>,
+    #[serde(default)]
+    backends: Vec<backend::BackendConfig>,
     probe_interval: u64,
     #[serde(default = "balancing::BalancingAlgorithm::round_robin")]
     balancing: balancing::BalancingAlgorithm,
+    #[serde(default)]
+    balancing_options: balancing::BalancingOptions,
+    #[serde(default = "Config::default_keep_alive_timeout")]
+    keep_alive_timeout: u64,
+    #[serde(default)]
+    connection_pool: backend::ConnectionPoolConfig,
+    #[serde(default)]
+    head_limits: http::HeadLimits,
+    #[serde(default)]
+    health_check: health::HealthCheck,
+    #[serde(default)]
+    outlier_detection: health::OutlierDetection,
+    #[serde(default)]
+    retry: retry::RetryPolicy,
+    #[serde(default)]
+    max_body_size: Option<u64>,
+    #[serde(default)]
+    error_pages: server::ErrorPages,
+    #[serde(default)]
+    timeouts: server::Timeouts,
+    #[serde(default)]
+    pools: BTreeMap<String, routing::PoolConfig>,
+    #[serde(default)]
+    routes: Vec<routing::RouteConfig>,
 }
 
 impl Config {
     pub fn from_file(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
         let f = std::fs::File::open(path)?;
         let config: Config = serde_yaml::from_reader(f)?;
-        return Ok(config);
+        Ok(config)
+    }
+
+    /// Check the settings of the configuration, without binding any socket or resolving any
+    /// address. The balancing algorithms and the routes are checked when building the
+    /// `Router`.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err` describing the first problem found, e.g. a malformed address, no
+    /// backends or an invalid health check.
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        validate_addr(&self.listen_on)?;
+        if self.backends.is_empty() && self.pools.is_empty() {
+            return Err("no backends configured".into());
+        }
+        self.health_check.validate()?;
+        self.outlier_detection.validate()?;
+        let pools = self.pools.values();
+        for backend in self
+            .backends
+            .iter()
+            .chain(pools.flat_map(|p| p.backends.iter()))
+        {
+            validate_addr(&backend.addr)?;
+            if let Some(health_check) = &backend.health_check {
+                health_check.validate()?;
+            }
+        }
+        for pool in self.pools.values() {
+            if let Some(health_check) = &pool.health_check {
+                health_check.validate()?;
+            }
+            if let Some(outlier_detection) = &pool.outlier_detection {
+                outlier_detection.validate()?;
+            }
+        }
+        Ok(())
     }
 
     pub fn listen_on(&self) -> &str {
         &self.listen_on
     }
 
-    pub fn backends(&self) -> &Vec<String> {
+    /// Override the address the load-balancer listens on
+    pub fn set_listen_on(&mut self, addr: String) {
+        self.listen_on = addr;
+    }
+
+    pub fn backends(&self) -> &Vec<backend::BackendConfig> {
         &self.backends
     }
 
+    /// Override the list of backends
+    pub fn set_backends(&mut self, backends: Vec<backend::BackendConfig>) {
+        self.backends = backends;
+    }
+
     pub fn probe_interval(&self) -> u64 {
         self.probe_interval
     }
@@ -64,6 +140,78 @@ impl Config {
     pub fn balancing_algorithm(&self) -> &balancing::BalancingAlgorithm {
         &self.balancing
     }
+
+    /// Idle timeout of client keep-alive connections in milliseconds
+    pub fn keep_alive_timeout(&self) -> u64 {
+        self.keep_alive_timeout
+    }
+
+    /// Limits of the upstream connection pool of each backend
+    pub fn connection_pool(&self) -> &backend::ConnectionPoolConfig {
+        &self.connection_pool
+    }
+
+    pub fn balancing_options(&self) -> &balancing::BalancingOptions {
+        &self.balancing_options
+    }
+
+    /// Limits of the heads of the HTTP messages
+    pub fn head_limits(&self) -> &http::HeadLimits {
+        &self.head_limits
+    }
+
+    /// Default health check of the backends
+    pub fn health_check(&self) -> &health::HealthCheck {
+        &self.health_check
+    }
+
+    /// Passive health checks of the backends, from the outcome of the requests
+    pub fn outlier_detection(&self) -> &health::OutlierDetection {
+        &self.outlier_detection
+    }
+
+    /// Retries of the failed requests on other backends
+    pub fn retry(&self) -> &retry::RetryPolicy {
+        &self.retry
+    }
+
+    /// Max size in bytes of the request bodies, larger requests are refused, chunked ones once
+    /// the limit is reached while they're streamed
+    pub fn max_body_size(&self) -> Option<u64> {
+        self.max_body_size
+    }
+
+    /// Responses generated when a request can't be forwarded to a backend
+    pub fn error_pages(&self) -> &server::ErrorPages {
+        &self.error_pages
+    }
+
+    /// Timeouts of the phases of the requests
+    pub fn timeouts(&self) -> &server::Timeouts {
+        &self.timeouts
+    }
+
+    /// Named pools of backends, besides the default one made of `backends`
+    pub fn pools(&self) -> &BTreeMap<String, routing::PoolConfig> {
+        &self.pools
+    }
+
+    /// Routes of the requests to the pools
+    pub fn routes(&self) -> &Vec<routing::RouteConfig> {
+        &self.routes
+    }
+
+    fn default_keep_alive_timeout() -> u64 {
+        5000
+    }
+}
+
+/// Check that `addr` is in the `host:port` form
+fn validate_addr(addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    match addr.rsplitn(2, ':').collect::<Vec<_>>().as_slice() {
+        [port, host] if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(format!("invalid address `{}`, expected `host:port`", addr).into()),
+    }
 }
 
 pub type AsyncResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
@@ -1,27 +1,99 @@
-use log::info;
-use rlb::backend::{Backend, BackendPool};
-use rlb::balancing::get_balancer;
-use rlb::server;
+use clap::{crate_version, App, AppSettings, Arg, SubCommand};
+use log::{info, LevelFilter};
+use rlb::backend::BackendConfig;
+use rlb::routing::Router;
+use rlb::server::{self, Settings};
 use rlb::Config;
+use std::process;
 use tokio::net::TcpListener;
 
 const CONF_PATH: &str = "config.yaml";
 
+fn cli() -> App<'static, 'static> {
+    App::new("rlb")
+        .version(crate_version!())
+        .about("Rust load-balancer, simple HTTP reverse-proxy")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("PATH")
+                .default_value(CONF_PATH)
+                .global(true)
+                .help("Path of the YAML configuration file"),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .value_name("ADDR")
+                .global(true)
+                .help("Address to listen on, overrides `listen_on` in the configuration"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .short("b")
+                .long("backend")
+                .value_name("ADDR")
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help(
+                    "Backend address, can be repeated, overrides `backends` in the configuration",
+                ),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .default_value("info")
+                .help("Max level of the log messages printed"),
+        )
+        .subcommand(SubCommand::with_name("check").about("Validate the configuration and exit"))
+}
+
 #[tokio::main]
 pub async fn main() -> rlb::AsyncResult<()> {
-    rlb::init_logging().expect("Can't enable logging");
-    let config = Config::from_file(CONF_PATH).expect("Error reading config.yaml");
-    let backends = config
-        .backends()
-        .iter()
-        .map(|b| Backend::new(b.to_string(), None))
-        .collect();
-    if let Ok(balancing_algo) = get_balancer(config.balancing_algorithm()) {
-        let pool = BackendPool::from_backends_list(backends, balancing_algo);
-        // Bind a TCP listener
-        let listener = TcpListener::bind(config.listen_on()).await?;
-        info!("Listening on {}", config.listen_on());
-        server::run(listener, pool, config.probe_interval()).await?
+    let matches = cli().get_matches();
+    let conf_path = matches.value_of("config").unwrap_or(CONF_PATH);
+    let mut config = match Config::from_file(conf_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", conf_path, e);
+            process::exit(1);
+        }
+    };
+    if let Some(addr) = matches.value_of("listen") {
+        config.set_listen_on(addr.to_string());
+    }
+    if let Some(backends) = matches.values_of("backend") {
+        config.set_backends(
+            backends
+                .map(|b| BackendConfig::new(b.to_string()))
+                .collect(),
+        );
+    }
+    // Building the router checks the balancing algorithms and the routes
+    let router = match config.validate().and_then(|_| Router::from_config(&config)) {
+        Ok(router) => router,
+        Err(e) => {
+            eprintln!("Invalid configuration {}: {}", conf_path, e);
+            process::exit(1);
+        }
+    };
+    if matches.subcommand_matches("check").is_some() {
+        println!("Configuration {} is valid", conf_path);
+        return Ok(());
     }
-    Ok(())
+    let level = matches
+        .value_of("log-level")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    rlb::init_logging(level).expect("Can't enable logging");
+    // Bind a TCP listener
+    let listener = TcpListener::bind(config.listen_on()).await?;
+    info!("Listening on {}", config.listen_on());
+    server::run(listener, router, Settings::from_config(&config)).await
 }
@@ -0,0 +1,126 @@
+/// Retries of the requests failing on a backend.
+///
+/// Provides the `RetryPolicy` configuration, telling which requests are retried on another
+/// backend and how many times, and the `RetryBudget` capping the share of retries over the
+/// requests served, so that retries don't pile up on an already overloaded pool.
+use crate::health::StatusRange;
+use crate::http::{HttpMessage, StatusCode};
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Span of time the retries are counted over by the budget
+const BUDGET_WINDOW: Duration = Duration::from_secs(1);
+
+/// Configuration of the retries.
+///
+/// Requests with an idempotent method are sent again to another backend when the connection
+/// fails or is reset, when the backend doesn't answer within `per_try_timeout` or answers with
+/// one of `statuses`, up to `max_retries` times. Request bodies are read before being sent so
+/// they can be sent again, only the ones up to `max_body_size` bytes, requests with larger or
+/// chunked bodies are not retried.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Max number of times a request is retried, 0 disables the retries
+    pub max_retries: usize,
+    /// Status codes of the responses retried
+    pub statuses: Vec<StatusRange>,
+    /// Time in milliseconds a backend has to answer to each try, from the connection to the
+    /// head of the response
+    pub per_try_timeout: Option<u64>,
+    /// Max size of the request bodies kept to be sent again
+    pub max_body_size: usize,
+    /// Max percentage of retries over the requests served
+    pub budget_percent: u32,
+    /// Retries allowed every second whatever the budget, so that a pool with little traffic
+    /// can retry too
+    pub min_retries_per_second: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 1,
+            statuses: vec![StatusRange::new(502, 504)],
+            per_try_timeout: None,
+            max_body_size: 64 * 1024,
+            budget_percent: 20,
+            min_retries_per_second: 10,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Return true if `request` can be retried: retries are enabled and its method is
+    /// idempotent
+    pub fn allows(&self, request: &HttpMessage) -> bool {
+        self.max_retries > 0 && request.method().is_some_and(|m| m.is_idempotent())
+    }
+
+    /// Return true if the responses with `status` are retried
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.statuses.iter().any(|r| r.contains(status))
+    }
+}
+
+#[derive(Debug)]
+struct BudgetWindow {
+    start: Instant,
+    requests: usize,
+    retries: usize,
+}
+
+/// Budget of the retries, shared by all the connections.
+///
+/// Every second at most `budget_percent` percent of the requests served in the same second can
+/// be retried, or `min_retries_per_second` retries if more.
+#[derive(Debug)]
+pub struct RetryBudget {
+    percent: u32,
+    min_retries: u32,
+    window: Mutex<BudgetWindow>,
+}
+
+impl RetryBudget {
+    pub fn new(policy: &RetryPolicy) -> RetryBudget {
+        RetryBudget {
+            percent: policy.budget_percent,
+            min_retries: policy.min_retries_per_second,
+            window: Mutex::new(BudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    fn current(&self) -> std::sync::MutexGuard<'_, BudgetWindow> {
+        let mut window = self.window.lock().unwrap();
+        if window.start.elapsed() >= BUDGET_WINDOW {
+            *window = BudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            };
+        }
+        window
+    }
+
+    /// Count a new request served, increasing the retries allowed
+    pub fn record_request(&self) {
+        self.current().requests += 1;
+    }
+
+    /// Take a retry from the budget, return false if the budget is exhausted
+    pub fn try_retry(&self) -> bool {
+        let mut window = self.current();
+        let allowed =
+            (window.requests * self.percent as usize / 100).max(self.min_retries as usize);
+        if window.retries >= allowed {
+            return false;
+        }
+        window.retries += 1;
+        true
+    }
+}
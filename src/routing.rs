@@ -0,0 +1,279 @@
+/// Routing of the requests to multiple backend pools.
+///
+/// Provides the `PoolConfig` and `RouteConfig` configurations, declaring named pools of
+/// backends, each with its own balancing algorithm and health checks, and the rules routing
+/// the requests to them, plus the `Router` matching each request against the rules.
+use crate::backend::{Backend, BackendConfig, BackendPool, ConnectionPoolConfig};
+use crate::balancing::{self, BalancingAlgorithm, BalancingOptions};
+use crate::health::{HealthCheck, OutlierDetection};
+use crate::http::HttpMessage;
+use crate::Config;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Name of the pool made of the top-level backends of the configuration
+pub const DEFAULT_POOL: &str = "default";
+
+/// Configuration of a named pool of backends.
+///
+/// Health checks and outlier detection default to the top-level ones of the configuration.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct PoolConfig {
+    pub backends: Vec<BackendConfig>,
+    #[serde(default = "BalancingAlgorithm::round_robin")]
+    pub balancing: BalancingAlgorithm,
+    #[serde(default)]
+    pub balancing_options: BalancingOptions,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetection>,
+}
+
+/// Configuration of a route: the requests matching all the conditions set are forwarded to
+/// `pool`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RouteConfig {
+    /// Host of the request, without port, e.g. `api.example.com`, or `*.example.com` for any
+    /// of its subdomains
+    pub host: Option<String>,
+    /// Prefix of the path of the request
+    pub path_prefix: Option<String>,
+    /// Regular expression the path of the request must match, query excluded
+    pub path_regex: Option<String>,
+    /// Methods of the request, any if empty
+    pub methods: Vec<String>,
+    /// Headers the request must carry, with their exact value
+    pub headers: HashMap<String, String>,
+    /// Name of the pool the requests are forwarded to
+    pub pool: String,
+}
+
+/// A route compiled from its configuration
+#[derive(Debug)]
+pub struct Route {
+    host: Option<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    methods: Vec<String>,
+    headers: Vec<(String, String)>,
+    pool: usize,
+}
+
+impl Route {
+    /// Create a route to the pool at index `pool` of the router.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err` if `path_regex` is not a valid regular expression.
+    pub fn from_config(config: &RouteConfig, pool: usize) -> Result<Route, Box<dyn Error>> {
+        let path_regex = match &config.path_regex {
+            Some(regex) => Some(
+                Regex::new(regex).map_err(|e| format!("invalid path regex `{}`: {}", regex, e))?,
+            ),
+            None => None,
+        };
+        Ok(Route {
+            host: config.host.as_ref().map(|h| h.to_ascii_lowercase()),
+            path_prefix: config.path_prefix.clone(),
+            path_regex,
+            methods: config.methods.clone(),
+            headers: config
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            pool,
+        })
+    }
+
+    /// Return true if `request` satisfies all the conditions of the route
+    pub fn matches(&self, request: &HttpMessage) -> bool {
+        let method = match request.method() {
+            Some(method) => method,
+            None => return false,
+        };
+        let route = method.route();
+        let path = route.split('?').next().unwrap_or_default();
+        self.host.as_ref().is_none_or(|host| {
+            request
+                .header("Host")
+                .is_some_and(|h| host_matches(host, h))
+        }) && self
+            .path_prefix
+            .as_ref()
+            .is_none_or(|prefix| path.starts_with(prefix.as_str()))
+            && self.path_regex.as_ref().is_none_or(|re| re.is_match(path))
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m == method.name()))
+            && self
+                .headers
+                .iter()
+                .all(|(name, value)| request.headers.get_all(name).any(|v| v == value))
+    }
+}
+
+/// Return true if the `Host` header `value` matches `pattern`, ignoring case and port. A
+/// pattern starting with `*.` matches any subdomain.
+fn host_matches(pattern: &str, value: &str) -> bool {
+    let host = match value.rsplit_once(':') {
+        // Skip the port, taking care of IPv6 literals like `[::1]`
+        Some((host, port)) if !port.contains(']') => host,
+        _ => value,
+    }
+    .to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => host == pattern,
+    }
+}
+
+/// Router of the requests to the pools of backends.
+///
+/// Routes are tried in the order they're declared, the first one matching a request selects
+/// its pool. Requests not matching any route go to the default pool, if any.
+#[derive(Default)]
+pub struct Router {
+    pools: Vec<(String, Arc<BackendPool>)>,
+    routes: Vec<Route>,
+    default: Option<usize>,
+}
+
+impl Router {
+    /// Create a new Router, without any pool
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Create a new Router forwarding every request to `pool`
+    pub fn with_default_pool(pool: BackendPool) -> Router {
+        let mut router = Router::new();
+        router.default = Some(router.push_pool(DEFAULT_POOL, pool));
+        router
+    }
+
+    /// Create the Router described by `config`: the pool of the top-level backends, used as
+    /// default, the named pools and the routes to them.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err` if a pool has no backends or an unknown balancing algorithm, if the name
+    /// of a pool is used twice or if a route is not valid or leads to an unknown pool.
+    pub fn from_config(config: &Config) -> Result<Router, Box<dyn Error>> {
+        let mut router = Router::new();
+        if !config.backends().is_empty() {
+            let pool = build_pool(
+                config.backends(),
+                config.balancing_algorithm(),
+                config.balancing_options(),
+                config.health_check(),
+                config.outlier_detection(),
+                config.connection_pool(),
+            )?;
+            router.default = Some(router.push_pool(DEFAULT_POOL, pool));
+        }
+        for (name, pool) in config.pools().iter() {
+            let pool = build_pool(
+                &pool.backends,
+                &pool.balancing,
+                &pool.balancing_options,
+                pool.health_check.as_ref().unwrap_or(config.health_check()),
+                pool.outlier_detection
+                    .as_ref()
+                    .unwrap_or(config.outlier_detection()),
+                config.connection_pool(),
+            )
+            .map_err(|e| format!("pool `{}`: {}", name, e))?;
+            router.add_pool(name, pool)?;
+        }
+        for route in config.routes().iter() {
+            router.add_route(route)?;
+        }
+        Ok(router)
+    }
+
+    fn push_pool(&mut self, name: &str, pool: BackendPool) -> usize {
+        self.pools.push((name.to_string(), Arc::new(pool)));
+        self.pools.len() - 1
+    }
+
+    /// Add a pool named `name`
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err` if there's already a pool with the same name.
+    pub fn add_pool(&mut self, name: &str, pool: BackendPool) -> Result<(), Box<dyn Error>> {
+        if self.pools.iter().any(|(n, _)| n == name) {
+            return Err(format!("duplicate pool `{}`", name).into());
+        }
+        self.push_pool(name, pool);
+        Ok(())
+    }
+
+    /// Add a route after the existing ones
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err` if the route leads to an unknown pool or is not valid.
+    pub fn add_route(&mut self, config: &RouteConfig) -> Result<(), Box<dyn Error>> {
+        let pool = self
+            .pools
+            .iter()
+            .position(|(name, _)| *name == config.pool)
+            .ok_or_else(|| format!("route to unknown pool `{}`", config.pool))?;
+        self.routes.push(Route::from_config(config, pool)?);
+        Ok(())
+    }
+
+    /// Return the pool `request` must be forwarded to, `None` if it matches no route and
+    /// there's no default pool.
+    pub fn route(&self, request: &HttpMessage) -> Option<&BackendPool> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(request))
+            .map(|route| route.pool)
+            .or(self.default)
+            .map(|index| self.pools[index].1.as_ref())
+    }
+
+    /// Return the pool named `name`
+    pub fn pool(&self, name: &str) -> Option<&Arc<BackendPool>> {
+        self.pools
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, pool)| pool)
+    }
+
+    /// Return the pools along with their names
+    pub fn pools(&self) -> impl Iterator<Item = (&str, &Arc<BackendPool>)> {
+        self.pools.iter().map(|(name, pool)| (name.as_str(), pool))
+    }
+}
+
+/// Create a pool of `backends` balanced by `algorithm`, checked with `health_check` unless a
+/// backend declares its own check.
+fn build_pool(
+    backends: &[BackendConfig],
+    algorithm: &BalancingAlgorithm,
+    options: &BalancingOptions,
+    health_check: &HealthCheck,
+    outlier_detection: &OutlierDetection,
+    connection_pool: &ConnectionPoolConfig,
+) -> Result<BackendPool, Box<dyn Error>> {
+    if backends.is_empty() {
+        return Err("no backends configured".into());
+    }
+    let backends = backends
+        .iter()
+        .map(|b| Backend::from_config(b, *connection_pool, health_check))
+        .collect();
+    let mut pool =
+        BackendPool::from_backends_list(backends, balancing::get_balancer(algorithm, options)?);
+    pool.set_outlier_detection(outlier_detection.clone());
+    Ok(pool)
+}
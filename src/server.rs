@@ -2,36 +2,376 @@
 ///
 /// Provides an async `run` function that instantiate a `Server` and listens for
 /// incoming connection, serving each one on a dedicated task.
-use crate::backend::{Backend, BackendPool};
-use crate::http::{parse_message, HttpMessage, HttpMethod, StatusCode};
-use crate::AsyncResult;
-use log::error;
-use std::net::Shutdown;
-use std::net::SocketAddr;
+use crate::backend::{BackendPool, Connection};
+use crate::balancing::RequestContext;
+use crate::health;
+use crate::http::{
+    copy_body, copy_body_limited, copy_chunk_encoded, read_message, BodyLength, HeadLimits,
+    HttpError, HttpHeader, HttpMessage, HttpMethod, HttpVersion, StatusCode,
+};
+use crate::retry::{RetryBudget, RetryPolicy};
+use crate::routing::Router;
+use crate::{AsyncResult, Config};
+use log::{debug, error, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::prelude::*;
-use tokio::sync::Mutex;
-use tokio::time::{self, delay_for, Duration};
-
-// Fixed read buffer size
-const BUFSIZE: usize = 2048;
+use tokio::time::{self, Delay, Duration, Instant};
 
 // Fixed size exponential backoff value
 const BACKOFF: u64 = 128;
 
+/// Configuration of the responses generated by the load-balancer itself, when a request can't
+/// be forwarded to a backend.
+///
+/// The body of each status can be set in `pages`, otherwise it's the status line as plain text.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ErrorPages {
+    /// `Content-Type` of the bodies
+    pub content_type: String,
+    /// Seconds the clients are told to wait with `Retry-After` before trying again when no
+    /// backend is available, 0 to not send the header
+    pub retry_after: u64,
+    /// Bodies of the responses by status code
+    pub pages: HashMap<u16, String>,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        ErrorPages {
+            content_type: "text/plain; charset=utf-8".to_string(),
+            retry_after: 5,
+            pages: HashMap::new(),
+        }
+    }
+}
+
+impl ErrorPages {
+    /// Create the response with `status`, carrying the page configured for it
+    pub fn response(&self, status: StatusCode) -> HttpMessage {
+        let body = match self.pages.get(&status.as_u16()) {
+            Some(page) => page.clone(),
+            None => format!("{} {}\n", status.as_u16(), status.reason()),
+        };
+        let mut response = HttpMessage::response(status, Default::default());
+        response.headers.insert("Content-Type", &self.content_type);
+        response
+            .headers
+            .insert("Content-Length", &body.len().to_string());
+        if status == StatusCode::SERVICE_UNAVAILABLE && self.retry_after > 0 {
+            response
+                .headers
+                .insert("Retry-After", &self.retry_after.to_string());
+        }
+        response.body = Some(body.into_bytes());
+        response
+    }
+}
+
+/// Timeouts of the phases of a request, in milliseconds, `null` to wait indefinitely. The time
+/// an idle client connection is kept open between two requests is `keep_alive_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Timeouts {
+    /// Time to receive the head of a request, once its first byte arrived
+    pub client_header: Option<u64>,
+    /// Time to receive the whole body of a request
+    pub client_body: Option<u64>,
+    /// Time to connect to a backend, waiting for a free connection included
+    pub connect: Option<u64>,
+    /// Time a backend has to start answering once the request has been sent
+    pub first_byte: Option<u64>,
+    /// Time a body being streamed, of a request or of a response, can go without receiving
+    /// any byte, or a response without the client accepting any byte
+    pub body_idle: Option<u64>,
+    /// Time to serve a request as a whole, from the reception of its head to the end of the
+    /// response
+    pub request: Option<u64>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            client_header: Some(10000),
+            client_body: Some(60000),
+            connect: Some(5000),
+            first_byte: Some(60000),
+            body_idle: Some(60000),
+            request: None,
+        }
+    }
+}
+
+/// Settings of the server, the part of the `Config` that doesn't describe the backends and the
+/// routes to them
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Healthcheck probe interval in milliseconds
+    pub probe_interval: u64,
+    /// Time in milliseconds an idle client connection is kept open waiting for the next request
+    pub keep_alive_timeout: u64,
+    /// Limits of the request heads read from the clients
+    pub head_limits: HeadLimits,
+    /// Max size in bytes of the request bodies
+    pub max_body_size: Option<u64>,
+    /// Retries of the failed requests
+    pub retry: RetryPolicy,
+    /// Responses sent when a request can't be forwarded
+    pub error_pages: ErrorPages,
+    /// Timeouts of the phases of the requests
+    pub timeouts: Timeouts,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            probe_interval: 5000,
+            keep_alive_timeout: 5000,
+            head_limits: HeadLimits::default(),
+            max_body_size: None,
+            retry: RetryPolicy::default(),
+            error_pages: ErrorPages::default(),
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Extract the settings of the server from `config`
+    pub fn from_config(config: &Config) -> Settings {
+        Settings {
+            probe_interval: config.probe_interval(),
+            keep_alive_timeout: config.keep_alive_timeout(),
+            head_limits: *config.head_limits(),
+            max_body_size: config.max_body_size(),
+            retry: config.retry().clone(),
+            error_pages: config.error_pages().clone(),
+            timeouts: *config.timeouts(),
+        }
+    }
+}
+
+/// Phase of a request that didn't complete in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Timeout {
+    ClientHeader,
+    ClientBody,
+    Connect,
+    FirstByte,
+    ResponseBody,
+    /// The client not reading the response
+    ClientWrite,
+    /// The per-try timeout of the retry policy
+    Try,
+    Request,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timeout::ClientHeader => write!(f, "timed out reading the request head"),
+            Timeout::ClientBody => write!(f, "timed out reading the request body"),
+            Timeout::Connect => write!(f, "timed out connecting to the backend"),
+            Timeout::FirstByte => write!(f, "timed out waiting for the response"),
+            Timeout::ResponseBody => write!(f, "timed out reading the response body"),
+            Timeout::ClientWrite => write!(f, "timed out writing the response"),
+            Timeout::Try => write!(f, "timed out waiting for the backend"),
+            Timeout::Request => write!(f, "timed out serving the request"),
+        }
+    }
+}
+
+impl Error for Timeout {}
+
+/// Return the phase that timed out if `error` is a timeout, possibly raised by an `IdleTimeout`
+/// stream and wrapped in an `io::Error`
+fn timeout_of(error: &(dyn Error + Send + Sync + 'static)) -> Option<Timeout> {
+    match error.downcast_ref::<io::Error>() {
+        Some(e) => e
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<Timeout>())
+            .copied(),
+        None => error.downcast_ref::<Timeout>().copied(),
+    }
+}
+
+/// Reader or writer failing with a `TimedOut` error wrapping the `phase` of its `IdleTimer`
+/// once it waited for too long without receiving or sending anything, to give up on bodies
+/// stalled half-way and on clients not reading their responses
+struct IdleTimeout<'a, S> {
+    stream: &'a mut S,
+    timer: IdleTimer,
+}
+
+impl<'a, S> IdleTimeout<'a, S> {
+    fn new(stream: &'a mut S, timeout: Option<u64>, phase: Timeout) -> Self {
+        IdleTimeout {
+            stream,
+            timer: IdleTimer {
+                timeout: timeout.map(Duration::from_millis),
+                phase,
+                delay: None,
+            },
+        }
+    }
+}
+
+/// Timer of an `IdleTimeout` stream, started when the stream starts waiting and stopped as soon
+/// as it makes progress
+struct IdleTimer {
+    timeout: Option<Duration>,
+    phase: Timeout,
+    /// Expiration of the current wait, `None` while the stream is not waiting
+    delay: Option<Delay>,
+}
+
+impl IdleTimer {
+    /// Return the timeout error once the stream waited for more than `timeout`
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        let delay = self.delay.get_or_insert_with(|| time::delay_for(timeout));
+        Pin::new(delay)
+            .poll(cx)
+            .map(|_| io::Error::new(io::ErrorKind::TimedOut, self.phase))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.stream).poll_read(cx, buf) {
+            Poll::Pending => this.timer.poll_expired(cx).map(Err),
+            ready => {
+                this.timer.delay = None;
+                ready
+            }
+        }
+    }
+}
+
+impl<S: AsyncBufRead + Unpin> AsyncBufRead for IdleTimeout<'_, S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.stream).poll_fill_buf(cx) {
+            Poll::Pending => this.timer.poll_expired(cx).map(Err),
+            ready => {
+                this.timer.delay = None;
+                ready
+            }
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut *self.get_mut().stream).consume(amt)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.stream).poll_write(cx, buf) {
+            Poll::Pending => this.timer.poll_expired(cx).map(Err),
+            ready => {
+                this.timer.delay = None;
+                ready
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.stream).poll_flush(cx) {
+            Poll::Pending => this.timer.poll_expired(cx).map(Err),
+            ready => {
+                this.timer.delay = None;
+                ready
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Run `future` for at most `timeout` and not past `deadline`, the end of the whole request.
+///
+/// # Errors
+///
+/// Return the error of `future` or `phase` if it didn't complete in time, `Timeout::Request`
+/// if it's the deadline that expired first.
+async fn within<F, T, E>(
+    future: F,
+    timeout: Option<u64>,
+    deadline: Option<Instant>,
+    phase: Timeout,
+) -> AsyncResult<T>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<Box<dyn Error + Send + Sync>>,
+{
+    let limit = timeout.map(|t| Instant::now() + Duration::from_millis(t));
+    let (limit, phase) = match (limit, deadline) {
+        (Some(limit), Some(deadline)) if deadline < limit => (Some(deadline), Timeout::Request),
+        (None, Some(deadline)) => (Some(deadline), Timeout::Request),
+        (limit, _) => (limit, phase),
+    };
+    match limit {
+        Some(limit) => match time::timeout_at(limit, future).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(phase.into()),
+        },
+        None => future.await.map_err(Into::into),
+    }
+}
+
+/// A request ready to be sent to a backend
+struct Outgoing<'a> {
+    /// Head of the request, followed by the part of its body already read
+    bytes: &'a [u8],
+    /// Framing of the rest of the body, still to be read from the client
+    length: BodyLength,
+    /// Whether the interim `1xx` responses of the backend are forwarded to the client,
+    /// HTTP/1.0 clients don't expect them
+    interim: bool,
+    /// Whether the request can be sent again if a reused connection turns out to be closed
+    idempotent: bool,
+}
+
 /// Server listener state. Created in the `run` call. It includes a `run` method
 /// which performs the TCP listening and initialization of per-connection state.
 struct Server {
     listener: TcpListener,
-    /// Healthcheck probe interval in milliseconds
-    interval: u64,
     /// Tcp exponential backoff threshold
     backoff: u64,
-    /// Shared pool handle. Contains the backends and the balancing algorithm chosen
-    /// at the start-up of the application. Being an Arc Mutex guarded it's allowed
-    /// to be cloned and locked in each task using it.
-    pool: Arc<Mutex<BackendPool>>,
+    /// Settings of the server, shared by all the connections
+    settings: Arc<Settings>,
+    /// Budget of the retries, shared by all the connections
+    retry_budget: Arc<RetryBudget>,
+    /// Shared router handle. Contains the pools of backends, each with the balancing
+    /// algorithm chosen at the start-up of the application. Their state is made of atomics
+    /// and short locks only, so it's shared as a plain Arc by each task using it.
+    router: Arc<Router>,
 }
 
 impl Server {
@@ -47,24 +387,20 @@ impl Server {
     /// operating system has reached an internal limit for max number of
     /// sockets, accept will fail.
     pub async fn run(&mut self) -> AsyncResult<()> {
-        // Let's spawn an healthcheck worker first
-        let mut probe_handler = Handler {
-            pool: self.pool.clone(),
-        };
-        let interval = self.interval;
-        tokio::spawn(async move {
-            if let Err(e) = probe_handler.probe_backends(interval).await {
-                error!("Can't spawn `probe_backends` worker: {}", e);
-            }
-        });
+        // Let's spawn the healthcheck workers first, one per backend of each pool
+        for (_, pool) in self.router.pools() {
+            health::spawn_probes(
+                pool.clone(),
+                Duration::from_millis(self.settings.probe_interval),
+                self.settings.head_limits,
+            );
+        }
         // Loop forever on new connections, accept them and pass the handling
         // to a worker
         loop {
             let stream = self.accept().await?;
             // Create the necessary per-connection handler state.
-            let handler = Handler {
-                pool: self.pool.clone(),
-            };
+            let handler = self.handler();
             // Spawn a new task to process the connections.
             tokio::spawn(async move {
                 if let Err(e) = handler.handle_connection(stream).await {
@@ -74,6 +410,15 @@ impl Server {
         }
     }
 
+    /// Create a new `Handler` sharing the router of the server
+    fn handler(&self) -> Handler {
+        Handler {
+            router: self.router.clone(),
+            settings: self.settings.clone(),
+            retry_budget: self.retry_budget.clone(),
+        }
+    }
+
     /// Accept an inbound connection.
     ///
     /// Errors are handled by backing off and retrying. An exponential backoff
@@ -109,140 +454,649 @@ impl Server {
 
 #[derive(Clone)]
 struct Handler {
-    /// Shared pool handle. Contains the backends and the balancing algorithm chosen
-    /// at the start-up of the application. It's used to call `next_backend` method
-    /// and route the requests incoming to the right backend.
-    pool: Arc<Mutex<BackendPool>>,
+    /// Shared router handle. Contains the pools of backends and the routes to them. It's
+    /// used to find the pool of each request, then to call its `next_backend` method and
+    /// route the requests incoming to the right backend.
+    router: Arc<Router>,
+    /// Settings of the server
+    settings: Arc<Settings>,
+    /// Budget of the retries, shared by all the connections
+    retry_budget: Arc<RetryBudget>,
 }
 
 impl Handler {
-    /// Try to connect to all registered backends in the balance pool.
+    /// Process a single connection.
     ///
-    /// The pool is the a shared mutable pointer guarded by a mutex.
-    async fn probe_backends(&mut self, interval: u64) -> AsyncResult<()> {
+    /// Serve requests from the client one after the other for as long as the connection can be
+    /// kept alive. For each request read the head, find the pool it's routed to and retrieve a
+    /// valid backend of the pool to forward the request to then call `forward_request` method
+    /// to stream the content to it and the response back. Pipelined requests are served in
+    /// order as they're read from the same buffered stream. The connection is closed once it
+    /// stays idle for `keep_alive_timeout`.
+    ///
+    /// Clients not sending the whole head of a request within the `client_header` timeout get a
+    /// `408 Request Timeout`. Malformed requests are answered with a `400 Bad Request`,
+    /// requests with too many or too large headers with a `431 Request Header Fields Too
+    /// Large`, requests of another version than HTTP/1.x with a `505 HTTP Version Not
+    /// Supported` and requests with a body larger than `max_body_size` with a `413 Payload Too
+    /// Large`, then the connection is closed.
+    /// Requests matching no route are answered with a `404 Not Found` and, if no backend of
+    /// their pool is available, with a `503 Service Unavailable`.
+    ///
+    /// # Errors
+    ///
+    /// Return an `Err` in case of error communicating with the client, connection can be
+    /// broken in the mean-time, or if the request is not valid.
+    async fn handle_connection(&self, stream: TcpStream) -> AsyncResult<()> {
+        let client_addr = stream.peer_addr().ok().map(|addr| addr.ip());
+        let mut client = BufReader::new(stream);
+        let keep_alive_timeout = Duration::from_millis(self.settings.keep_alive_timeout);
         loop {
-            // Add a scope to automatically drop the mutex lock before the sleep,
-            // alternatively call `drop(pool)` by hand
-            let mut pool = self.pool.lock().await;
-            let mut buffer = [0; BUFSIZE];
-            // Iterating through all the backends and try to connect to each one, if an error
-            // in connection is raised, mark the backend as offline.
-            // Also if there's an healthcheck endpoint set for the backend, after a
-            // successfull connection try to query the endpoint, if the response is different
-            // from a `200 OK` mark the backend as offline.
-            for backend in pool.iter_mut() {
-                let backend_addr: SocketAddr = backend
-                    .addr
-                    .parse()
-                    .expect("Unable to parse backend address");
-                match TcpStream::connect(&backend_addr).await {
-                    // Connection OK, now check if an health_endpoint is set
-                    // and try to query it
-                    Ok(mut stream) => match backend.health_endpoint() {
-                        Some(h) => {
-                            let request = HttpMessage::new(
-                                HttpMethod::Get(h.clone()),
-                                [("Host".to_string(), backend.addr.to_string())]
-                                    .iter()
-                                    .cloned()
-                                    .collect(),
-                            );
-                            stream.write_all(format!("{}", request).as_bytes()).await?;
-                            stream.read(&mut buffer).await?;
-                            let response = parse_message(&buffer).unwrap();
-                            // Health endpoint response inspection
-                            if response.status_code() == Some(StatusCode::new(200)) {
-                                backend.set_online()
-                            } else {
-                                backend.set_offline()
-                            }
+            // Wait for the next request, giving up if nothing shows up within the keep-alive
+            // timeout
+            let next = poll_fn(|cx| {
+                Pin::new(&mut client)
+                    .poll_fill_buf(cx)
+                    .map_ok(|buf| buf.is_empty())
+            });
+            match time::timeout(keep_alive_timeout, next).await {
+                Ok(Ok(false)) => (),
+                Ok(Ok(true)) => return Ok(()),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {
+                    debug!(
+                        "Closing client connection idle since {}ms",
+                        self.settings.keep_alive_timeout
+                    );
+                    return Ok(());
+                }
+            }
+            // Read request head from the client (frontend connection)
+            let read = read_message(&mut client, &self.settings.head_limits);
+            let timeout = self.settings.timeouts.client_header;
+            let request = match within(read, timeout, None, Timeout::ClientHeader).await {
+                Ok(Some((request, _))) => request,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    // There's no way to find where the next request starts, the connection is
+                    // closed right after the response
+                    let parsing = e.downcast_ref::<io::Error>().and_then(HttpError::from_io);
+                    let status = match (timeout_of(&*e), parsing) {
+                        (Some(_), _) => {
+                            warn!("Closing client connection: {}", e);
+                            self.send_error(&mut client, StatusCode::REQUEST_TIMEOUT, None, false)
+                                .await?;
+                            return Ok(());
+                        }
+                        (_, Some(HttpError::HeadTooLarge))
+                        | (_, Some(HttpError::TooManyHeaders)) => {
+                            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                        }
+                        (_, Some(HttpError::UnsupportedVersion)) => {
+                            StatusCode::HTTP_VERSION_NOT_SUPPORTED
                         }
-                        None => backend.set_online(),
-                    },
-                    Err(_) => backend.set_offline(),
+                        (_, Some(_)) => StatusCode::BAD_REQUEST,
+                        (None, None) => return Err(e),
+                    };
+                    warn!("Invalid request head: {}", e);
+                    self.send_error(&mut client, status, None, false).await?;
+                    return Ok(());
+                }
+            };
+            // Requests whose body can't be delimited for sure are rejected, as there's no way to
+            // find where the next one starts
+            let length = match request.body_length() {
+                Ok(length) => length,
+                Err(e) => {
+                    warn!("Invalid body framing of {}: {}", request.header, e);
+                    let status = StatusCode::BAD_REQUEST;
+                    self.send_error(&mut client, status, Some(&request), false)
+                        .await?;
+                    return Ok(());
+                }
+            };
+            if let (Some(max), BodyLength::Fixed(length)) = (self.settings.max_body_size, length) {
+                if length > max {
+                    warn!("Request body of {} bytes over the limit of {}", length, max);
+                    let status = StatusCode::PAYLOAD_TOO_LARGE;
+                    self.send_error(&mut client, status, Some(&request), false)
+                        .await?;
+                    return Ok(());
                 }
             }
-            drop(pool);
-            // Sleep for a defined timeout
-            delay_for(Duration::from_millis(interval)).await;
+            // Find the pool of the request and select a valid backend according to its
+            // balancing rules, every request on the connection goes through the selection again
+            let ctx = RequestContext::new(&request, client_addr);
+            let selected = match self.router.route(&request) {
+                Some(pool) => match pool.next_backend_for(&ctx) {
+                    Ok(index) => Ok((pool, index)),
+                    Err(_) => {
+                        warn!("No backend available for {}", request.header);
+                        Err(StatusCode::SERVICE_UNAVAILABLE)
+                    }
+                },
+                None => {
+                    warn!("No route for {}", request.header);
+                    Err(StatusCode::NOT_FOUND)
+                }
+            };
+            let (pool, index) = match selected {
+                Ok(selected) => selected,
+                Err(status) => {
+                    // The connection is kept alive only if there's no request body to skip
+                    let keep_alive = request.keep_alive() && length == BodyLength::Empty;
+                    self.send_error(&mut client, status, Some(&request), keep_alive)
+                        .await?;
+                    if keep_alive {
+                        continue;
+                    }
+                    return Ok(());
+                }
+            };
+            // Forward the request to the selected backend and stream the response back to the
+            // client
+            let keep_alive = self
+                .forward_request(request, length, &mut client, pool, index)
+                .await?;
+            if !keep_alive {
+                return Ok(());
+            }
         }
     }
 
-    /// Process a single connection.
+    /// Handle request from a client, forward it to a selected backend and stream the response
+    /// back to the client, by correcting the `Host` header before forward (not very elegant).
+    /// Bodies are copied between the two sockets as they arrive, following the framing declared
+    /// by the headers of each message (`Content-Length` or `Transfer-Encoding: chunked`).
+    ///
+    /// The upstream connection is taken from the backend pool and handed back to it once the
+    /// response is complete, unless the backend asked to close it or switched protocols, as
+    /// upgraded connections are not tunnelled.
+    ///
+    /// Clients sending `Expect: 100-continue` get the `100 Continue` from the load-balancer
+    /// itself, so that the body is streamed without waiting for the backend to ask for it.
     ///
-    /// First retrieve a valid backend to forward the request to then call `forward_request` method
-    /// to forward the content to it and read the response back.
+    /// Requests allowed by the retry policy are sent again to another backend if the backend
+    /// fails, doesn't answer within the per-try timeout or answers with one of the statuses to
+    /// retry, as long as the retry budget is not exhausted. Their bodies are read before
+    /// sending them, to be able to send them again.
+    ///
+    /// The outcome of the request is recorded on the backend for the outlier detection:
+    /// connection errors, backends closing the connection without answering and `5xx`
+    /// responses count as failures. Errors while streaming a request body are not counted, as
+    /// they can't be told apart from the client going away.
+    ///
+    /// If the backend fails before answering, the client gets a `502 Bad Gateway`, or a `504
+    /// Gateway Timeout` if the backend didn't answer in time. Clients not sending the request
+    /// body in time get a `408 Request Timeout`. Once the `request` timeout expires the request
+    /// is abandoned, closing the connection if the response was already being streamed, as
+    /// when the response body stalls for longer than `body_idle` or the client doesn't read
+    /// any of it for as long.
+    ///
+    /// The hop-by-hop headers of the response are replaced by the ones of the client connection.
+    /// Return `true` if the client connection can be reused for another request, that is when
+    /// the request allows it and the end of the response body is not marked by closing the
+    /// connection. Such bodies are re-encoded in chunks for HTTP/1.1 clients, keeping their
+    /// connection alive.
     ///
     /// # Errors
     ///
-    /// If no backend are available return an `Err`, this can happen if all backends result
-    /// offline. Also return an `Err` in caswe of error reading from the selected backend,
-    /// connection can be broken in the mean-time.
-    async fn handle_connection(&self, mut stream: TcpStream) -> AsyncResult<()> {
-        let mut pool = self.pool.lock().await;
-        let mut buffer = [0; BUFSIZE];
-        // Read request from the client (frontend connection)
-        stream.read(&mut buffer).await?;
-        // Select a valid backend according to the balancing rules
-        let index = match pool.next_backend() {
-            Ok(i) => i,
-            Err(e) => {
-                stream.shutdown(Shutdown::Both)?;
-                return Err(Box::new(e));
+    /// Return an `Err` in case of communication errors with the client, or with the backend
+    /// once the response started to be streamed back.
+    async fn forward_request(
+        &self,
+        mut request: HttpMessage,
+        mut request_length: BodyLength,
+        client: &mut BufReader<TcpStream>,
+        pool: &BackendPool,
+        mut index: usize,
+    ) -> AsyncResult<bool> {
+        let deadline = self
+            .settings
+            .timeouts
+            .request
+            .map(|t| Instant::now() + Duration::from_millis(t));
+        let keep_alive = request.keep_alive();
+        // The upstream connection is always kept alive to be pooled
+        remove_hop_by_hop(&mut request);
+        request.headers.insert("Connection", "keep-alive");
+        // `Transfer-Encoding` takes precedence, a `Content-Length` could mislead the backend
+        if request_length == BodyLength::Chunked {
+            request.headers.remove("Content-Length");
+        }
+        let interim = request.http_version() == Some(&HttpVersion::V11);
+        let idempotent = request.method().is_some_and(HttpMethod::is_idempotent);
+        // Clients expecting a `100 Continue` before sending the body are answered right away,
+        // the body is streamed to the backend without waiting for it to ask for it
+        if request.headers.has_token("Expect", "100-continue") {
+            request.headers.remove("Expect");
+            if interim && request_length != BodyLength::Empty {
+                let response = HttpMessage::response(StatusCode::CONTINUE, Default::default());
+                let head = response.head_bytes();
+                let write = client.write_all(&head);
+                let idle = self.settings.timeouts.body_idle;
+                if let Err(e) = within(write, idle, deadline, Timeout::ClientWrite).await {
+                    return self.fail_request(client, &request, pool, index, e).await;
+                }
             }
+        }
+        // Bodies of the requests that can be retried are read upfront, so that they can be sent
+        // again to another backend
+        let mut body = Vec::new();
+        let mut retryable = self.settings.retry.allows(&request);
+        if retryable {
+            match request_length {
+                BodyLength::Empty => (),
+                BodyLength::Fixed(n) if n <= self.settings.retry.max_body_size as u64 => {
+                    let read = async {
+                        let idle = self.settings.timeouts.body_idle;
+                        let mut reader = IdleTimeout::new(client, idle, Timeout::ClientBody);
+                        copy_body(&mut reader, &mut body, request_length).await
+                    };
+                    let timeout = self.settings.timeouts.client_body;
+                    if let Err(e) = within(read, timeout, deadline, Timeout::ClientBody).await {
+                        return self.fail_request(client, &request, pool, index, e).await;
+                    }
+                    request_length = BodyLength::Empty;
+                }
+                _ => retryable = false,
+            }
+        }
+        self.retry_budget.record_request();
+        let mut tried = Vec::new();
+        let (index, _in_flight, start, mut conn, mut response) = loop {
+            let backend = &pool[index];
+            // Count the request as in flight on the backend until the response has been
+            // streamed
+            let in_flight = backend.start_request();
+            // Update the `Host` header on the request to be forwarded
+            request.headers.insert("Host", &backend.addr);
+            let mut request_bytes = request.to_bytes();
+            request_bytes.extend_from_slice(&body);
+            let start = Instant::now();
+            let outgoing = Outgoing {
+                bytes: &request_bytes,
+                length: request_length,
+                interim,
+                idempotent,
+            };
+            let attempt = self.try_backend(pool, index, &outgoing, client, deadline);
+            let outcome = within(
+                attempt,
+                self.settings.retry.per_try_timeout,
+                deadline,
+                Timeout::Try,
+            )
+            .await;
+            if let Err(e) = &outcome {
+                if timeout_of(&**e) == Some(Timeout::Try) {
+                    pool.record_outcome(index, false);
+                }
+            }
+            tried.push(index);
+            let reason = match outcome {
+                Ok((conn, response)) => {
+                    let status = response.status_code();
+                    let failed = status.is_some_and(|s| s.as_u16() >= 500);
+                    pool.record_outcome(index, !failed);
+                    match status {
+                        Some(status) if retryable && self.settings.retry.retries_status(status) => {
+                            match self.next_try(pool, &tried) {
+                                Some(next) => {
+                                    warn!(
+                                        "Backend {} answered {}, retrying on {}",
+                                        backend.addr,
+                                        status.as_u16(),
+                                        pool[next].addr
+                                    );
+                                    index = next;
+                                    continue;
+                                }
+                                None => break (index, in_flight, start, conn, response),
+                            }
+                        }
+                        _ => break (index, in_flight, start, conn, response),
+                    }
+                }
+                // Once the request timed out there's no time left for another try, clients not
+                // reading the interim responses are not worth another one
+                Err(e)
+                    if retryable
+                        && !matches!(
+                            timeout_of(&*e),
+                            Some(Timeout::Request) | Some(Timeout::ClientWrite)
+                        ) =>
+                {
+                    e
+                }
+                Err(e) => return self.fail_request(client, &request, pool, index, e).await,
+            };
+            match self.next_try(pool, &tried) {
+                Some(next) => {
+                    warn!(
+                        "Backend {} failed: {}, retrying on {}",
+                        backend.addr, reason, pool[next].addr
+                    );
+                    index = next;
+                }
+                None => {
+                    return self
+                        .fail_request(client, &request, pool, index, reason)
+                        .await
+                }
+            }
+        };
+        let backend = &pool[index];
+        let ttfb = start.elapsed();
+        // Responses to `HEAD` requests never carry a body, whatever their headers say
+        let length = match request.method() {
+            Some(HttpMethod::Head(_)) => BodyLength::Empty,
+            // The framing of the response was checked by `send_request`
+            _ => response.body_length()?,
+        };
+        // `Transfer-Encoding` takes precedence, a `Content-Length` could mislead the client
+        if response.transfer_encoding().is_some() {
+            response.headers.remove("Content-Length");
+        }
+        // Connections switching to another protocol are not tunnelled, they're closed once the
+        // response is forwarded
+        let upgraded = response.status_code() == Some(StatusCode::SWITCHING_PROTOCOLS);
+        let reusable = response.keep_alive() && length != BodyLength::UntilClose && !upgraded;
+        // The end of the body is marked by the backend closing the connection, HTTP/1.1 clients
+        // get it encoded in chunks instead so that their connection can be kept alive
+        let chunk_encode = length == BodyLength::UntilClose
+            && keep_alive
+            && request.http_version() == Some(&HttpVersion::V11);
+        let keep_alive =
+            keep_alive && (length != BodyLength::UntilClose || chunk_encode) && !upgraded;
+        // The hop-by-hop headers of the backend are replaced by the ones of the client
+        // connection
+        remove_hop_by_hop(&mut response);
+        if !keep_alive {
+            response.headers.insert("Connection", "close");
+        } else if request.http_version() == Some(&HttpVersion::V10) {
+            response.headers.insert("Connection", "keep-alive");
+        }
+        // The body keeps the codings applied by the backend, e.g. `gzip`, chunked comes last
+        if chunk_encode {
+            if let HttpHeader::Status(version, _) = &mut response.header {
+                *version = HttpVersion::V11;
+            }
+            let mut codings: Vec<&str> = response.headers.get_all("Transfer-Encoding").collect();
+            codings.push("chunked");
+            let codings = codings.join(", ");
+            response.headers.insert("Transfer-Encoding", &codings);
+            response.headers.remove("Content-Length");
+        }
+        let head = response.head_bytes();
+        let stream = async {
+            let idle = self.settings.timeouts.body_idle;
+            let mut writer = IdleTimeout::new(client, idle, Timeout::ClientWrite);
+            writer.write_all(&head).await?;
+            let mut reader = IdleTimeout::new(&mut conn.stream, idle, Timeout::ResponseBody);
+            let body = if chunk_encode {
+                copy_chunk_encoded(&mut reader, &mut writer).await?
+            } else {
+                copy_body(&mut reader, &mut writer, length).await?
+            };
+            Ok::<_, io::Error>(head.len() as u64 + body)
         };
-        // Forward the request to the selected backend and forward the response back to the client
-        let response = self.forward_request(&buffer, &mut pool[index]).await?;
-        stream.write_all(&response).await?;
-        Ok(())
+        // The response is already on its way, the client only notices the connection closed
+        let bytesin = match within(stream, None, deadline, Timeout::Request).await {
+            Ok(bytesin) => bytesin,
+            Err(e) if timeout_of(&*e).is_some() => {
+                warn!("Closing client connection: {}", e);
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        backend.increase_byte_traffic(bytesin as usize);
+        backend.record_latency(ttfb, start.elapsed());
+        if reusable {
+            backend.release(conn);
+        }
+        Ok(keep_alive)
     }
 
-    /// Handle request from a client, forward it to a selected backend and response
-    /// back to the client, by correcting the `Host` header before forward (not very elegant).
-    /// Expects the headers of the response, handling `Chunked` responses with multiple `read`
-    /// calls.
+    /// Send the request to the backend at `index` and read the head of its final response.
     ///
-    /// # Errors
+    /// A reused connection could have been closed by the backend while idle, in that case
+    /// idempotent requests without a body are sent again on a new connection, unless the
+    /// backend timed out. Other requests may have been processed already.
+    /// Failures of the backend are recorded for the outlier detection.
+    ///
+    /// Return the connection along with the head of the response.
+    async fn try_backend(
+        &self,
+        pool: &BackendPool,
+        index: usize,
+        request: &Outgoing<'_>,
+        client: &mut BufReader<TcpStream>,
+        deadline: Option<Instant>,
+    ) -> AsyncResult<(Connection, HttpMessage)> {
+        let backend = &pool[index];
+        loop {
+            let connect = backend.connection();
+            let timeout = self.settings.timeouts.connect;
+            let mut conn = match within(connect, timeout, deadline, Timeout::Connect).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    pool.record_outcome(index, false);
+                    return Err(e);
+                }
+            };
+            let resend =
+                conn.is_reused() && request.length == BodyLength::Empty && request.idempotent;
+            match self
+                .send_request(&mut conn, request, client, deadline)
+                .await
+            {
+                Ok((bytesout, Some(response))) => {
+                    // Log traffic on the backend
+                    backend.increase_byte_traffic(bytesout as usize);
+                    return Ok((conn, response));
+                }
+                Ok((_, None)) if resend => continue,
+                Err(e) if resend && timeout_of(&*e).is_none() => continue,
+                Ok((_, None)) => {
+                    pool.record_outcome(index, false);
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "backend closed the connection",
+                    )
+                    .into());
+                }
+                Err(e) => {
+                    // Errors streaming a request body can come from the client
+                    let failed = match timeout_of(&*e) {
+                        Some(Timeout::ClientBody) | Some(Timeout::ClientWrite) => false,
+                        Some(_) => true,
+                        None => request.length == BodyLength::Empty,
+                    };
+                    if failed {
+                        pool.record_outcome(index, false);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Answer the client after the request to the backend at `index` failed with `error`: `408
+    /// Request Timeout` if the client didn't send the body in time, `413 Payload Too Large` if
+    /// the body turned out to be larger than `max_body_size`, `504 Gateway Timeout` if the
+    /// backend didn't answer in time, `502 Bad Gateway` otherwise. The connection is closed, as
+    /// the request body may have not been completely read, right away if the client doesn't
+    /// read what it's sent.
+    async fn fail_request(
+        &self,
+        client: &mut BufReader<TcpStream>,
+        request: &HttpMessage,
+        pool: &BackendPool,
+        index: usize,
+        error: Box<dyn Error + Send + Sync>,
+    ) -> AsyncResult<bool> {
+        let parsing = error
+            .downcast_ref::<io::Error>()
+            .and_then(HttpError::from_io);
+        let status = match timeout_of(&*error) {
+            Some(Timeout::ClientWrite) => {
+                warn!("Closing client connection: {}", error);
+                return Ok(false);
+            }
+            Some(Timeout::ClientHeader) | Some(Timeout::ClientBody) => {
+                warn!("Request {} failed: {}", request.header, error);
+                StatusCode::REQUEST_TIMEOUT
+            }
+            None if parsing == Some(&HttpError::BodyTooLarge) => {
+                warn!("Request {} failed: {}", request.header, error);
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Some(_) => {
+                error!("Backend {} failed: {}", pool[index].addr, error);
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            None => {
+                error!("Backend {} failed: {}", pool[index].addr, error);
+                StatusCode::BAD_GATEWAY
+            }
+        };
+        self.send_error(client, status, Some(request), false)
+            .await?;
+        Ok(false)
+    }
+
+    /// Send the error response with `status` to the client. Responses to `HEAD` requests
+    /// carry no body, the connection is marked to be closed unless `keep_alive` is set.
+    async fn send_error(
+        &self,
+        client: &mut BufReader<TcpStream>,
+        status: StatusCode,
+        request: Option<&HttpMessage>,
+        keep_alive: bool,
+    ) -> io::Result<()> {
+        let mut response = self.settings.error_pages.response(status);
+        if !keep_alive {
+            response.headers.insert("Connection", "close");
+        }
+        if let Some(HttpMethod::Head(_)) = request.and_then(|r| r.method()) {
+            response.body = None;
+        }
+        client.write_all(&response.to_bytes()).await
+    }
+
+    /// Return the backend to retry a request on, after it failed on the backends in `tried`.
+    /// `None` if the request was retried `max_retries` times already, no other backend is
+    /// available or the retry budget is exhausted.
+    fn next_try(&self, pool: &BackendPool, tried: &[usize]) -> Option<usize> {
+        if tried.len() > self.settings.retry.max_retries {
+            return None;
+        }
+        let index = pool.next_backend_excluding(tried).ok()?;
+        if !self.retry_budget.try_retry() {
+            warn!("Retry budget exhausted");
+            return None;
+        }
+        Some(index)
+    }
+
+    /// Write the request head, along with the part of the body already read if any, and stream
+    /// the rest of its body from the client to the backend connection, then read the head of
+    /// the final response.
+    ///
+    /// Interim `1xx` responses preceding it, e.g. `100 Continue` or `103 Early Hints`, are
+    /// forwarded to the client if it supports them and skipped otherwise. `101 Switching
+    /// Protocols` is final, as it ends the HTTP exchanges on the connection.
     ///
-    /// Return an `Err` in case of communication errors with the backend (unable to read data or
-    /// write it).
-    async fn forward_request(&self, buffer: &[u8], backend: &mut Backend) -> AsyncResult<Vec<u8>> {
-        let backend_addr: SocketAddr = backend
-            .addr
-            .parse()
-            .expect("Unable to parse backend address");
-        let mut request = parse_message(buffer).unwrap();
-        // Update the `Host` header on the request to be forwarded
-        *request.headers.get_mut("Host").unwrap() = backend.addr.to_string();
-        let mut response_buf = [0; BUFSIZE];
-        let mut stream = TcpStream::connect(&backend_addr).await?;
-        // Log traffic on the backend
-        let bytesout = stream.write(format!("{}", request).as_bytes()).await?;
-        backend.increase_byte_traffic(bytesout);
-        let mut read_bytes = stream.read(&mut response_buf).await?;
-        let response = parse_message(&response_buf).unwrap();
-        // Multiple read till the message is completed in CHUNKED mode
-        if response.transfer_encoding().unwrap_or(&"".to_string()) == "chunked" {
-            while response_buf[read_bytes - 5..read_bytes] != [b'0', b'\r', b'\n', b'\r', b'\n'] {
-                read_bytes += stream.read(&mut response_buf[read_bytes..]).await?;
-            }
-        }
-        backend.increase_byte_traffic(read_bytes);
-        stream.shutdown(Shutdown::Both)?;
-        return Ok(response_buf.to_vec());
+    /// The body must be received within the `client_body` timeout, without stalling for longer
+    /// than `body_idle`, and can't be larger than `max_body_size`, which chunked bodies are
+    /// only checked against while they're streamed.
+    /// The backend must start answering within the `first_byte` timeout once the request is
+    /// sent.
+    ///
+    /// Return the number of bytes sent and the head of the response, `None` if the backend
+    /// closed the connection without answering.
+    async fn send_request(
+        &self,
+        conn: &mut Connection,
+        request: &Outgoing<'_>,
+        client: &mut BufReader<TcpStream>,
+        deadline: Option<Instant>,
+    ) -> AsyncResult<(u64, Option<HttpMessage>)> {
+        conn.stream.write_all(request.bytes).await?;
+        let copy = async {
+            let idle = self.settings.timeouts.body_idle;
+            let mut reader = IdleTimeout::new(client, idle, Timeout::ClientBody);
+            match self.settings.max_body_size {
+                Some(max) => {
+                    copy_body_limited(&mut reader, &mut conn.stream, request.length, max).await
+                }
+                None => copy_body(&mut reader, &mut conn.stream, request.length).await,
+            }
+        };
+        let timeout = self.settings.timeouts.client_body;
+        let bytesout = request.bytes.len() as u64
+            + within(copy, timeout, deadline, Timeout::ClientBody).await?;
+        loop {
+            let read = read_message(&mut conn.stream, &self.settings.head_limits);
+            let timeout = self.settings.timeouts.first_byte;
+            let mut response = match within(read, timeout, deadline, Timeout::FirstByte).await? {
+                Some((response, _)) => response,
+                None => return Ok((bytesout, None)),
+            };
+            match response.status_code() {
+                Some(status) if status.is_interim() => {
+                    if request.interim {
+                        remove_hop_by_hop(&mut response);
+                        let head = response.head_bytes();
+                        let write = client.write_all(&head);
+                        let idle = self.settings.timeouts.body_idle;
+                        within(write, idle, deadline, Timeout::ClientWrite).await?;
+                    }
+                }
+                _ => {
+                    // Responses whose body can't be delimited are not forwarded
+                    response.body_length()?;
+                    return Ok((bytesout, Some(response)));
+                }
+            }
+        }
+    }
+}
+
+/// Headers never removed for being listed in `Connection`, as the framing of the message and
+/// its routing depend on them
+const END_TO_END: [&str; 3] = ["Content-Length", "Transfer-Encoding", "Host"];
+
+/// Remove the hop-by-hop headers of a message, `Connection`, the headers it lists and
+/// `Keep-Alive`, which only apply to the connection it was received from. The framing
+/// headers and `Host` are kept even if listed.
+fn remove_hop_by_hop(message: &mut HttpMessage) {
+    let hop_by_hop: Vec<String> = message
+        .headers
+        .get_all("Connection")
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim().to_string())
+        .filter(|t| !END_TO_END.iter().any(|h| h.eq_ignore_ascii_case(t)))
+        .collect();
+    for name in hop_by_hop.iter() {
+        message.headers.remove(name);
     }
+    message.headers.remove("Connection");
+    message.headers.remove("Keep-Alive");
 }
 
 /// Run a tokio async server, accepts and handle new connections asynchronously.
 ///
-/// Arguments are listener, a bound `TcpListener` and pool a `BackendPool` with type
-/// `LoadBalancing`
-pub async fn run(listener: TcpListener, pool: BackendPool, interval: u64) -> AsyncResult<()> {
+/// Arguments are listener, a bound `TcpListener`, router a `Router` holding the pools of
+/// backends and the routes to them and the `Settings` of the server
+pub async fn run(listener: TcpListener, router: Router, settings: Settings) -> AsyncResult<()> {
     let mut server = Server {
         listener,
-        interval,
         backoff: BACKOFF,
-        pool: Arc::new(Mutex::new(pool)),
+        retry_budget: Arc::new(RetryBudget::new(&settings.retry)),
+        settings: Arc::new(settings),
+        router: Arc::new(router),
     };
     server.run().await?;
     Ok(())
@@ -1,11 +1,18 @@
-use rlb::backend::{Backend, BackendError, BackendPool};
+mod common;
+
+use rlb::backend::{Backend, BackendError, BackendPool, ConnectionPoolConfig};
 use rlb::balancing::RoundRobinBalancing;
-use std::sync::atomic::Ordering;
+use rlb::health::HealthState;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[test]
 fn backend_new_test() {
     let backend = Backend::new(String::from(":5000"), Some(String::from("/health")));
-    assert_eq!(backend.alive.load(Ordering::Acquire), false);
+    assert!(!backend.is_alive());
+    assert_eq!(backend.health_state(), HealthState::Unknown);
     assert_eq!(backend.byte_traffic(), 0);
     assert_eq!(backend.health_endpoint(), &Some("/health".to_string()));
 }
@@ -32,7 +39,7 @@ fn backend_pool_from_list() {
 
 #[test]
 fn backend_pool_next_backend_round_robin() {
-    let mut pool = BackendPool::from_backends_list(
+    let pool = BackendPool::from_backends_list(
         vec![
             Backend::new(String::from(":5000"), None),
             Backend::new(String::from(":5001"), None),
@@ -41,7 +48,140 @@ fn backend_pool_next_backend_round_robin() {
     );
     let index = pool.next_backend();
     assert_eq!(index, Err(BackendError::NoBackendAlive));
-    pool[1].alive.store(true, Ordering::Relaxed);
+    pool[1].set_online();
     let index = pool.next_backend();
     assert_eq!(index, Ok(1));
 }
+
+#[test]
+fn backend_pool_next_backend_excluding() {
+    let pool = BackendPool::from_backends_list(
+        vec![
+            Backend::new(String::from(":5000"), None),
+            Backend::new(String::from(":5001"), None),
+            Backend::new(String::from(":5002"), None),
+        ],
+        Box::new(RoundRobinBalancing::new()),
+    );
+    for backend in pool.iter() {
+        backend.set_online();
+    }
+    for _ in 0..4 {
+        assert_eq!(pool.next_backend_excluding(&[0, 1]), Ok(2));
+    }
+    assert_ne!(pool.next_backend_excluding(&[1]), Ok(1));
+    assert_eq!(
+        pool.next_backend_excluding(&[0, 1, 2]),
+        Err(BackendError::NoBackendAlive)
+    );
+}
+
+#[tokio::test]
+async fn backend_connection_reuse() {
+    let addr = common::blackhole().await;
+    let backend = Backend::new(addr, None);
+    let conn = backend.connection().await.unwrap();
+    assert!(!conn.is_reused());
+    assert_eq!(backend.idle_connections(), 0);
+    backend.release(conn);
+    assert_eq!(backend.idle_connections(), 1);
+    let conn = backend.connection().await.unwrap();
+    assert!(conn.is_reused());
+    assert_eq!(backend.idle_connections(), 0);
+    // Connections holding bytes not read yet are not reused
+    let backend = Backend::new(common::backend("XY").await, None);
+    let mut conn = backend.connection().await.unwrap();
+    conn.stream.write_all(b"ping").await.unwrap();
+    let mut first = [0; 1];
+    conn.stream.read_exact(&mut first).await.unwrap();
+    backend.release(conn);
+    assert_eq!(backend.idle_connections(), 0);
+}
+
+#[tokio::test]
+async fn backend_connection_pool_limits() {
+    let addr = common::blackhole().await;
+    let config = ConnectionPoolConfig {
+        max_idle: 1,
+        idle_timeout: 0,
+        ..ConnectionPoolConfig::default()
+    };
+    let backend = Backend::with_connection_pool(addr, None, config);
+    let first = backend.connection().await.unwrap();
+    let second = backend.connection().await.unwrap();
+    backend.release(first);
+    backend.release(second);
+    assert_eq!(backend.idle_connections(), 1);
+    // Idle timeout is 0, the idle connection is expired and a new one is opened
+    let conn = backend.connection().await.unwrap();
+    assert!(!conn.is_reused());
+    assert_eq!(backend.idle_connections(), 0);
+}
+
+#[test]
+fn backend_pool_shared_next_backend() {
+    let pool = Arc::new(BackendPool::from_backends_list(
+        vec![
+            Backend::new(String::from(":5000"), None),
+            Backend::new(String::from(":5001"), None),
+        ],
+        Box::new(RoundRobinBalancing::new()),
+    ));
+    for backend in pool.iter() {
+        backend.set_online();
+    }
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                (0..100)
+                    .map(|_| pool.next_backend().unwrap())
+                    .filter(|&i| i == 0)
+                    .count()
+            })
+        })
+        .collect();
+    let hits: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    // Round robin is shared by all the threads, exactly half of the requests hit each backend
+    assert_eq!(hits, 200);
+}
+
+#[tokio::test]
+async fn backend_load_counters() {
+    let addr = common::blackhole().await;
+    let backend = Backend::new(addr, None);
+    assert_eq!(backend.active_connections(), 0);
+    let first = backend.connection().await.unwrap();
+    let second = backend.connection().await.unwrap();
+    assert_eq!(backend.active_connections(), 2);
+    // Idle connections are not active
+    backend.release(first);
+    assert_eq!(backend.active_connections(), 1);
+    drop(second);
+    assert_eq!(backend.active_connections(), 0);
+    {
+        let _first = backend.start_request();
+        let _second = backend.start_request();
+        assert_eq!(backend.in_flight(), 2);
+    }
+    assert_eq!(backend.in_flight(), 0);
+    backend.increase_byte_traffic(10);
+    backend.increase_byte_traffic(20);
+    assert_eq!(backend.byte_traffic(), 30);
+}
+
+#[test]
+fn backend_latency_ewma() {
+    let backend = Backend::new(String::from(":5000"), None);
+    assert_eq!(backend.ttfb(), 0.0);
+    backend.record_latency(Duration::from_millis(100), Duration::from_millis(150));
+    assert_eq!(backend.ttfb(), 100.0);
+    assert_eq!(backend.latency(), 150.0);
+    // Peaks replace the average right away
+    backend.record_latency(Duration::from_millis(200), Duration::from_millis(250));
+    assert_eq!(backend.ttfb(), 200.0);
+    // Lower samples lower it gradually, depending on the time elapsed
+    backend.record_latency(Duration::from_millis(10), Duration::from_millis(20));
+    assert!(backend.ttfb() < 200.0 && backend.ttfb() > 150.0);
+    assert!(backend.latency() < 250.0 && backend.latency() > 200.0);
+}
@@ -1,11 +1,22 @@
+mod common;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rlb::backend::Backend;
-use rlb::balancing::{HashingBalancing, LeastTrafficBalancing, LoadBalancing, RoundRobinBalancing};
-use rlb::http::{HttpMessage, HttpMethod};
-use std::sync::atomic::Ordering;
+use rlb::balancing::{
+    get_balancer, BalancingAlgorithm, BalancingOptions, ConsistentHashing, HashKey,
+    HashingBalancing, LeastConnectionsBalancing, LeastLatencyBalancing, LeastRequestsBalancing,
+    LeastTrafficBalancing, LoadBalancing, LoadMetric, PowerOfTwoChoicesBalancing,
+    RendezvousHashing, RequestContext, RoundRobinBalancing, WeightedRandomBalancing,
+    WeightedRoundRobinBalancing,
+};
+use rlb::http::{Headers, HttpMessage, HttpMethod};
+use std::net::IpAddr;
+use std::time::Duration;
 
 #[test]
 fn round_robin_test() {
-    let mut rr_algo = RoundRobinBalancing::new();
+    let rr_algo = RoundRobinBalancing::new();
     let backends = vec![
         Backend::new(String::from(":5000"), None),
         Backend::new(String::from(":5001"), None),
@@ -15,22 +26,30 @@ fn round_robin_test() {
     let index = rr_algo.next_backend(&backends);
     assert_eq!(index, None);
     for backend in backends.iter() {
-        backend.alive.store(true, Ordering::Relaxed);
+        backend.set_online();
     }
     let index = rr_algo.next_backend(&backends).unwrap();
+    assert_eq!(index, 0);
+    let index = rr_algo.next_backend(&backends).unwrap();
     assert_eq!(index, 1);
     let index = rr_algo.next_backend(&backends).unwrap();
     assert_eq!(index, 2);
     let index = rr_algo.next_backend(&backends).unwrap();
     assert_eq!(index, 3);
+    // Offline backends are skipped
+    backends[0].set_offline();
     let index = rr_algo.next_backend(&backends).unwrap();
-    assert_eq!(index, 0);
+    assert_eq!(index, 2);
+    let index = rr_algo.next_backend(&backends).unwrap();
+    assert_eq!(index, 3);
+    let index = rr_algo.next_backend(&backends).unwrap();
+    assert_eq!(index, 1);
 }
 
 #[test]
 fn least_traffic_test() {
-    let mut rr_algo = LeastTrafficBalancing;
-    let mut backends = vec![
+    let rr_algo = LeastTrafficBalancing;
+    let backends = vec![
         Backend::new(String::from(":5000"), None),
         Backend::new(String::from(":5001"), None),
         Backend::new(String::from(":5002"), None),
@@ -43,7 +62,7 @@ fn least_traffic_test() {
     let index = rr_algo.next_backend(&backends);
     assert_eq!(index, None);
     for backend in backends.iter() {
-        backend.alive.store(true, Ordering::Relaxed);
+        backend.set_online();
     }
     let index = rr_algo.next_backend(&backends).unwrap();
     assert_eq!(index, 1);
@@ -58,18 +77,386 @@ fn hashing_test() {
             .cloned()
             .collect(),
     );
-    let mut rr_algo = HashingBalancing::new(&request);
+    let client: IpAddr = "10.0.0.1".parse().unwrap();
+    let ctx = RequestContext::new(&request, Some(client));
+    let rr_algo = HashingBalancing::new(HashKey::ClientIp);
     let backends = vec![
         Backend::new(String::from(":5000"), None),
         Backend::new(String::from(":5001"), None),
         Backend::new(String::from(":5002"), None),
         Backend::new(String::from(":5003"), None),
     ];
-    let index = rr_algo.next_backend(&backends);
+    let index = rr_algo.next_backend_for(&backends, &ctx);
     assert_eq!(index, None);
     for backend in backends.iter() {
-        backend.alive.store(true, Ordering::Relaxed);
+        backend.set_online();
     }
-    let index = rr_algo.next_backend(&backends).unwrap();
-    assert_eq!(index, 3);
+    // The same client always lands on the same backend, also after a restart as the hash doesn't
+    // depend on the process
+    let index = rr_algo.next_backend_for(&backends, &ctx).unwrap();
+    assert_eq!(index, 1);
+    for _ in 0..10 {
+        assert_eq!(rr_algo.next_backend_for(&backends, &ctx), Some(index));
+    }
+    // Different clients spread over the backends
+    let mut hit = vec![false; backends.len()];
+    for i in 0..64 {
+        let client: IpAddr = format!("10.0.1.{}", i).parse().unwrap();
+        let ctx = RequestContext::new(&request, Some(client));
+        hit[rr_algo.next_backend_for(&backends, &ctx).unwrap()] = true;
+    }
+    assert!(hit.iter().all(|h| *h));
+}
+
+#[test]
+fn hash_key_extract_test() {
+    let request = HttpMessage::new(
+        HttpMethod::Get("/users/42?lang=en&session=abc".to_string()),
+        [
+            ("Host".to_string(), "localhost".to_string()),
+            ("X-User-Id".to_string(), "42".to_string()),
+            ("Cookie".to_string(), "theme=dark; sid=xyz".to_string()),
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    );
+    let client: IpAddr = "10.0.0.1".parse().unwrap();
+    let ctx = RequestContext::new(&request, Some(client));
+    assert_eq!(
+        HashKey::ClientIp.extract(&ctx),
+        Some("10.0.0.1".to_string())
+    );
+    assert_eq!(HashKey::Path.extract(&ctx), Some("/users/42".to_string()));
+    assert_eq!(
+        HashKey::Header("x-user-id".to_string()).extract(&ctx),
+        Some("42".to_string())
+    );
+    assert_eq!(
+        HashKey::Cookie("sid".to_string()).extract(&ctx),
+        Some("xyz".to_string())
+    );
+    assert_eq!(
+        HashKey::Query("session".to_string()).extract(&ctx),
+        Some("abc".to_string())
+    );
+    assert_eq!(HashKey::Query("missing".to_string()).extract(&ctx), None);
+    assert_eq!(HashKey::Cookie("missing".to_string()).extract(&ctx), None);
+}
+
+fn alive_backends(n: usize) -> Vec<Backend> {
+    (0..n)
+        .map(|i| {
+            let backend = Backend::new(format!("10.0.0.{}:80", i), None);
+            backend.set_online();
+            backend
+        })
+        .collect()
+}
+
+/// Map 1000 paths to the address of the backend selected for each of them
+fn map_keys(algo: &dyn LoadBalancing, backends: &[Backend]) -> Vec<String> {
+    (0..1000)
+        .map(|i| {
+            let request = HttpMessage::new(HttpMethod::Get(format!("/item/{}", i)), Headers::new());
+            let ctx = RequestContext::new(&request, None);
+            let index = algo.next_backend_for(backends, &ctx).unwrap();
+            backends[index].addr.clone()
+        })
+        .collect()
+}
+
+/// Check that growing a pool of 5 backends by one moves only keys to the new backend, roughly
+/// a sixth of them, and that shrinking it back only moves the keys of the removed backend.
+fn check_bounded_movement(algo: &dyn LoadBalancing) {
+    let five = alive_backends(5);
+    let six = alive_backends(6);
+    let before = map_keys(algo, &five);
+    let after = map_keys(algo, &six);
+    let moved: Vec<_> = before
+        .iter()
+        .zip(after.iter())
+        .filter(|(b, a)| b != a)
+        .collect();
+    assert!(moved.iter().all(|(_, a)| *a == "10.0.0.5:80"));
+    assert!(
+        moved.len() > 80 && moved.len() < 250,
+        "moved {}",
+        moved.len()
+    );
+    assert_eq!(map_keys(algo, &five), before);
+    // A dead backend only has its own keys remapped
+    five[2].set_offline();
+    let dead = map_keys(algo, &five);
+    for (b, d) in before.iter().zip(dead.iter()) {
+        if b == "10.0.0.2:80" {
+            assert_ne!(d, b);
+        } else {
+            assert_eq!(d, b);
+        }
+    }
+}
+
+#[test]
+fn consistent_hashing_test() {
+    let algo = ConsistentHashing::new(HashKey::Path, 160);
+    let backends = alive_backends(5);
+    let keys = map_keys(&algo, &backends);
+    // Deterministic across runs and spread over all the backends
+    assert_eq!(map_keys(&algo, &backends), keys);
+    for backend in backends.iter() {
+        let owned = keys.iter().filter(|k| **k == backend.addr).count();
+        assert!(owned > 100 && owned < 300, "owned {}", owned);
+    }
+    check_bounded_movement(&algo);
+}
+
+#[test]
+fn rendezvous_hashing_test() {
+    let algo = RendezvousHashing::new(HashKey::Path);
+    let backends = alive_backends(5);
+    for backend in backends.iter() {
+        let owned = map_keys(&algo, &backends)
+            .iter()
+            .filter(|k| **k == backend.addr)
+            .count();
+        assert!(owned > 100 && owned < 300, "owned {}", owned);
+    }
+    check_bounded_movement(&algo);
+    for backend in backends.iter() {
+        backend.set_offline();
+    }
+    assert_eq!(algo.next_backend(&backends), None);
+}
+
+fn weighted_backends(weights: &[u32]) -> Vec<Backend> {
+    let mut backends = alive_backends(weights.len());
+    for (backend, weight) in backends.iter_mut().zip(weights) {
+        backend.set_weight(*weight);
+    }
+    backends
+}
+
+#[test]
+fn weighted_round_robin_test() {
+    let algo = WeightedRoundRobinBalancing::new();
+    let backends = weighted_backends(&[5, 1, 1, 0]);
+    // Smooth sequence, the heavier backend is interleaved with the others
+    let sequence: Vec<_> = (0..7)
+        .map(|_| algo.next_backend(&backends).unwrap())
+        .collect();
+    assert_eq!(sequence, vec![0, 0, 1, 0, 2, 0, 0]);
+    // Drained and offline backends are skipped
+    backends[0].set_offline();
+    for _ in 0..10 {
+        let index = algo.next_backend(&backends).unwrap();
+        assert!(index == 1 || index == 2);
+    }
+    backends[1].set_offline();
+    backends[2].set_offline();
+    assert_eq!(algo.next_backend(&backends), None);
+}
+
+#[test]
+fn weighted_random_test() {
+    let algo = WeightedRandomBalancing::new();
+    let backends = weighted_backends(&[3, 1, 0]);
+    let mut hits = vec![0; backends.len()];
+    for _ in 0..4000 {
+        hits[algo.next_backend(&backends).unwrap()] += 1;
+    }
+    assert_eq!(hits[2], 0);
+    assert!(hits[0] > 2 * hits[1], "hits {:?}", hits);
+    backends[0].set_offline();
+    backends[1].set_offline();
+    assert_eq!(algo.next_backend(&backends), None);
+}
+
+#[test]
+fn least_requests_test() {
+    let algo = LeastRequestsBalancing::new();
+    let backends = alive_backends(3);
+    let _first = backends[0].start_request();
+    let _second = backends[0].start_request();
+    let _third = backends[2].start_request();
+    assert_eq!(algo.next_backend(&backends), Some(1));
+    backends[1].set_offline();
+    assert_eq!(algo.next_backend(&backends), Some(2));
+    // Ties rotate between the backends
+    let _fourth = backends[2].start_request();
+    backends[1].set_online();
+    let _fifth = backends[1].start_request();
+    let _sixth = backends[1].start_request();
+    let picks: Vec<_> = (0..4)
+        .map(|_| algo.next_backend(&backends).unwrap())
+        .collect();
+    assert!(picks.contains(&0) && picks.contains(&1) && picks.contains(&2));
+}
+
+#[tokio::test]
+async fn least_connections_test() {
+    let addr = common::blackhole().await;
+    let mut backends: Vec<_> = (0..2)
+        .map(|_| {
+            let backend = Backend::new(addr.clone(), None);
+            backend.set_online();
+            backend
+        })
+        .collect();
+    backends[0].set_weight(4);
+    let mut conns = Vec::new();
+    for _ in 0..3 {
+        conns.push(backends[0].connection().await.unwrap());
+    }
+    conns.push(backends[1].connection().await.unwrap());
+    // 3 connections against 1
+    let algo = LeastConnectionsBalancing::new(false);
+    assert_eq!(algo.next_backend(&backends), Some(1));
+    // 3 / 4 against 1 / 1
+    let algo = LeastConnectionsBalancing::new(true);
+    assert_eq!(algo.next_backend(&backends), Some(0));
+    backends[0].set_weight(0);
+    assert_eq!(algo.next_backend(&backends), Some(1));
+}
+
+#[test]
+fn power_of_two_choices_test() {
+    let algo = PowerOfTwoChoicesBalancing::new(LoadMetric::InFlight);
+    let backends = alive_backends(2);
+    // With two backends both are always sampled, the least loaded wins
+    let _first = backends[0].start_request();
+    for _ in 0..20 {
+        assert_eq!(algo.next_backend(&backends), Some(1));
+    }
+    // Never selects the most loaded of a larger pool
+    let backends = alive_backends(8);
+    let _busy: Vec<_> = (0..3).map(|_| backends[5].start_request()).collect();
+    let mut hits = vec![0; backends.len()];
+    for _ in 0..400 {
+        hits[algo.next_backend(&backends).unwrap()] += 1;
+    }
+    assert_eq!(hits[5], 0);
+    assert!(hits.iter().filter(|h| **h > 0).count() == 7);
+    // Offline backends are skipped, also when only one is left
+    for backend in backends.iter().skip(1) {
+        backend.set_offline();
+    }
+    assert_eq!(algo.next_backend(&backends), Some(0));
+    backends[0].set_offline();
+    assert_eq!(algo.next_backend(&backends), None);
+}
+
+#[test]
+fn least_latency_test() {
+    let algo = LeastLatencyBalancing::new();
+    let backends = alive_backends(3);
+    backends[0].record_latency(Duration::from_millis(20), Duration::from_millis(30));
+    backends[1].record_latency(Duration::from_millis(50), Duration::from_millis(60));
+    // Backends never measured are tried first
+    assert_eq!(algo.next_backend(&backends), Some(2));
+    backends[2].record_latency(Duration::from_millis(200), Duration::from_millis(250));
+    assert_eq!(algo.next_backend(&backends), Some(0));
+    // 20ms with 2 requests in flight costs more than 50ms with none
+    let _first = backends[0].start_request();
+    let _second = backends[0].start_request();
+    assert_eq!(algo.next_backend(&backends), Some(1));
+    let p2c = PowerOfTwoChoicesBalancing::new(LoadMetric::Latency);
+    backends[2].set_offline();
+    assert_eq!(p2c.next_backend(&backends), Some(1));
+}
+
+#[test]
+fn least_latency_unmeasured_test() {
+    let algo = LeastLatencyBalancing::new();
+    let backends = alive_backends(2);
+    backends[0].record_latency(Duration::from_millis(20), Duration::from_millis(30));
+    // Requests in flight to a backend never measured cost the average of the measured ones
+    let _busy: Vec<_> = (0..5).map(|_| backends[1].start_request()).collect();
+    for _ in 0..10 {
+        assert_eq!(algo.next_backend(&backends), Some(0));
+    }
+    let p2c = PowerOfTwoChoicesBalancing::new(LoadMetric::Latency);
+    for _ in 0..10 {
+        assert_eq!(p2c.next_backend(&backends), Some(0));
+    }
+    // Without any sample, they are compared on the requests in flight
+    let backends = alive_backends(2);
+    let _busy = backends[0].start_request();
+    assert_eq!(algo.next_backend(&backends), Some(1));
+    assert_eq!(p2c.next_backend(&backends), Some(1));
+}
+
+fn all_algorithms() -> Vec<BalancingAlgorithm> {
+    vec![
+        BalancingAlgorithm::round_robin(),
+        BalancingAlgorithm::random(),
+        BalancingAlgorithm::least_traffic(),
+        BalancingAlgorithm::weighted_round_robin(),
+        BalancingAlgorithm::weighted_random(),
+        BalancingAlgorithm::least_connections(),
+        BalancingAlgorithm::weighted_least_connections(),
+        BalancingAlgorithm::least_requests(),
+        BalancingAlgorithm::power_of_two_choices(),
+        BalancingAlgorithm::least_latency(),
+        BalancingAlgorithm::hashing(),
+        BalancingAlgorithm::consistent_hashing(),
+        BalancingAlgorithm::rendezvous(),
+    ]
+}
+
+/// For every algorithm and many random pools, with random alive/dead patterns, weights and
+/// loads, a selection always terminates with an available backend, or `None` if there's none.
+#[test]
+fn select_only_available_property() {
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    let options = BalancingOptions::default();
+    for algorithm in all_algorithms() {
+        let algo = get_balancer(&algorithm, &options).unwrap();
+        for _ in 0..300 {
+            let len = rng.gen_range(1, 12);
+            let backends: Vec<_> = (0..len)
+                .map(|i| {
+                    let mut backend = Backend::new(format!("10.0.0.{}:80", i), None);
+                    if rng.gen_bool(0.5) {
+                        backend.set_online();
+                    }
+                    backend.set_weight(rng.gen_range(0, 4));
+                    backend.increase_byte_traffic(rng.gen_range(0, 1000));
+                    backend
+                })
+                .collect();
+            let _in_flight: Vec<_> = backends
+                .iter()
+                .filter(|_| rng.gen_bool(0.3))
+                .map(|b| b.start_request())
+                .collect();
+            let any_available = backends.iter().any(|b| b.is_available());
+            let request = HttpMessage::new(
+                HttpMethod::Get(format!("/item/{}", rng.gen::<u16>())),
+                Headers::new(),
+            );
+            let ctx = RequestContext::new(&request, None);
+            for index in [
+                algo.next_backend(&backends),
+                algo.next_backend_for(&backends, &ctx),
+            ] {
+                match index {
+                    Some(i) => assert!(backends[i].is_available(), "{:?}", algorithm),
+                    None => assert!(!any_available, "{:?}", algorithm),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn least_traffic_dead_min_test() {
+    let algo = LeastTrafficBalancing::new();
+    let backends = alive_backends(3);
+    backends[0].increase_byte_traffic(10);
+    backends[1].increase_byte_traffic(30);
+    backends[2].increase_byte_traffic(20);
+    // The least loaded backend is offline, the next one is selected right away
+    backends[0].set_offline();
+    assert_eq!(algo.next_backend(&backends), Some(2));
 }
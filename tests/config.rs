@@ -0,0 +1,120 @@
+use rlb::backend::{Backend, BackendConfig, ConnectionPoolConfig};
+use rlb::balancing::{BalancingAlgorithm, HashKey};
+use rlb::health::HealthState;
+use rlb::Config;
+
+const CONF: &str = r#"
+listen_on: "127.0.0.1:6767"
+backends:
+    - "127.0.0.1:7892"
+    - "127.0.0.1:9898"
+probe_interval: 5000
+balancing: round-robin
+"#;
+
+#[test]
+fn config_defaults_test() {
+    let config: Config = serde_yaml::from_str(CONF).unwrap();
+    assert_eq!(config.listen_on(), "127.0.0.1:6767");
+    assert_eq!(config.backends().len(), 2);
+    assert_eq!(
+        *config.balancing_algorithm(),
+        BalancingAlgorithm::round_robin()
+    );
+    assert_eq!(config.keep_alive_timeout(), 5000);
+    assert_eq!(config.balancing_options().hash_key, HashKey::ClientIp);
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn config_overrides_validate_test() {
+    let mut config: Config = serde_yaml::from_str(CONF).unwrap();
+    config.set_listen_on("0.0.0.0:8080".to_string());
+    config.set_backends(vec![BackendConfig::new("backend-1:80".to_string())]);
+    assert_eq!(config.listen_on(), "0.0.0.0:8080");
+    assert_eq!(config.backends()[0].addr, "backend-1:80");
+    assert_eq!(config.backends()[0].weight, 1);
+    assert!(config.validate().is_ok());
+    config.set_listen_on("8080".to_string());
+    assert!(config.validate().is_err());
+    config.set_listen_on(":8080".to_string());
+    assert!(config.validate().is_err());
+    config.set_listen_on("0.0.0.0:8080".to_string());
+    config.set_backends(vec![]);
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn config_hashing_key_test() {
+    let conf = format!(
+        "{}balancing_options:\n    hash_key:\n        cookie: sid\n",
+        CONF.replace("round-robin", "hashing")
+    );
+    let config: Config = serde_yaml::from_str(&conf).unwrap();
+    assert_eq!(*config.balancing_algorithm(), BalancingAlgorithm::hashing());
+    assert_eq!(
+        config.balancing_options().hash_key,
+        HashKey::Cookie("sid".to_string())
+    );
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn config_backend_weights_test() {
+    let conf = r#"
+listen_on: "127.0.0.1:6767"
+backends:
+    - "127.0.0.1:7892"
+    - addr: "127.0.0.1:9898"
+      weight: 3
+    - addr: "127.0.0.1:9899"
+      weight: 0
+    - addr: "127.0.0.1:9900"
+      drain: true
+probe_interval: 5000
+balancing: weighted-round-robin
+"#;
+    let config: Config = serde_yaml::from_str(conf).unwrap();
+    let weights: Vec<_> = config.backends().iter().map(|b| b.weight).collect();
+    assert_eq!(weights, vec![1, 3, 0, 1]);
+    let drained: Vec<_> = config.backends().iter().map(|b| b.drain).collect();
+    assert_eq!(drained, vec![false, false, false, true]);
+    // A drained backend stays out of the pool whatever the outcome of the health checks
+    let backend = Backend::from_config(
+        &config.backends()[3],
+        ConnectionPoolConfig::default(),
+        config.health_check(),
+    );
+    assert_eq!(backend.health_state(), HealthState::Draining);
+    assert_eq!(backend.record_health_check(true), None);
+    assert!(!backend.is_available());
+    assert_eq!(config.backends()[1].addr, "127.0.0.1:9898");
+    assert_eq!(
+        *config.balancing_algorithm(),
+        BalancingAlgorithm::weighted_round_robin()
+    );
+}
+
+#[test]
+fn config_health_check_test() {
+    let conf = format!(
+        "{}health_check:\n    path: /health\n    timeout: 500\n",
+        CONF.replace(
+            "    - \"127.0.0.1:9898\"\n",
+            "    - addr: \"127.0.0.1:9898\"\n      health_check:\n        path: /ready\n        method: HEAD\n"
+        )
+    );
+    let config: Config = serde_yaml::from_str(&conf).unwrap();
+    assert_eq!(config.health_check().path, Some("/health".to_string()));
+    assert_eq!(config.health_check().timeout, 500);
+    let pool = ConnectionPoolConfig::default();
+    let backends: Vec<_> = config
+        .backends()
+        .iter()
+        .map(|b| Backend::from_config(b, pool, config.health_check()))
+        .collect();
+    assert_eq!(backends[0].health_check(), config.health_check());
+    assert_eq!(backends[1].health_check().path, Some("/ready".to_string()));
+    assert_eq!(backends[1].health_check().method, "HEAD");
+    assert!(config.validate().is_ok());
+}
@@ -0,0 +1,258 @@
+mod common;
+
+use common::{backend, blackhole};
+use rlb::backend::{Backend, BackendPool};
+use rlb::balancing::RoundRobinBalancing;
+use rlb::health::{
+    check, spawn_probes, Health, HealthCheck, HealthError, HealthState, Outlier, OutlierDetection,
+    StatusRange,
+};
+use rlb::http::HeadLimits;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::time::{delay_for, Duration};
+
+#[test]
+fn health_check_config_test() {
+    let conf = r#"
+path: /health
+method: HEAD
+expected_statuses: [200, "301-302", "2xx"]
+body_contains: "ok"
+timeout: 500
+host: "api.local"
+"#;
+    let health_check: HealthCheck = serde_yaml::from_str(conf).unwrap();
+    assert_eq!(health_check.path, Some("/health".to_string()));
+    assert_eq!(health_check.method, "HEAD");
+    assert_eq!(
+        health_check.expected_statuses,
+        vec![
+            StatusRange::new(200, 200),
+            StatusRange::new(301, 302),
+            StatusRange::new(200, 299)
+        ]
+    );
+    assert_eq!(health_check.timeout, 500);
+    assert_eq!(health_check.host, Some("api.local".to_string()));
+    assert!(health_check.validate().is_ok());
+    let defaults: HealthCheck = serde_yaml::from_str("path: /health").unwrap();
+    assert_eq!(defaults, HealthCheck::http("/health"));
+    assert!(serde_yaml::from_str::<HealthCheck>("expected_statuses: [\"2yy\"]").is_err());
+    assert!(serde_yaml::from_str::<HealthCheck>("expected_statuses: [\"300-200\"]").is_err());
+}
+
+#[tokio::test]
+async fn health_check_probe_test() {
+    let limits = HeadLimits::default();
+    let addr = backend("HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nstatus ok").await;
+    // Plain connection
+    assert!(check(&addr, &HealthCheck::default(), &limits).await.is_ok());
+    let mut health_check = HealthCheck::http("/health");
+    assert!(check(&addr, &health_check, &limits).await.is_ok());
+    health_check.body_contains = Some("ok".to_string());
+    assert!(check(&addr, &health_check, &limits).await.is_ok());
+    health_check.body_contains = Some("ready".to_string());
+    assert!(matches!(
+        check(&addr, &health_check, &limits).await,
+        Err(HealthError::UnexpectedBody)
+    ));
+    let addr = backend("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+    let mut health_check = HealthCheck::http("/health");
+    assert!(matches!(
+        check(&addr, &health_check, &limits).await,
+        Err(HealthError::UnexpectedStatus(_))
+    ));
+    health_check
+        .expected_statuses
+        .push(StatusRange::new(500, 599));
+    assert!(check(&addr, &health_check, &limits).await.is_ok());
+}
+
+#[tokio::test]
+async fn health_check_failures_test() {
+    let limits = HeadLimits::default();
+    // Accepts connections but never answers
+    let addr = blackhole().await;
+    let mut health_check = HealthCheck::http("/health");
+    health_check.timeout = 100;
+    assert!(matches!(
+        check(&addr, &health_check, &limits).await,
+        Err(HealthError::Timeout)
+    ));
+    // Nothing listening
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    assert!(matches!(
+        check(&addr, &health_check, &limits).await,
+        Err(HealthError::Connect(_))
+    ));
+}
+
+#[test]
+fn health_state_transitions_test() {
+    let health = Health::new();
+    assert_eq!(health.state(), HealthState::Unknown);
+    // The first check decides the state of an unknown backend
+    assert_eq!(
+        health.record(true, 2, 3),
+        Some((HealthState::Unknown, HealthState::Healthy))
+    );
+    // Healthy falls after 3 consecutive failures, a success resets the count
+    assert_eq!(health.record(false, 2, 3), None);
+    assert_eq!(health.record(false, 2, 3), None);
+    assert_eq!(health.record(true, 2, 3), None);
+    assert_eq!(health.record(false, 2, 3), None);
+    assert_eq!(health.record(false, 2, 3), None);
+    assert_eq!(
+        health.record(false, 2, 3),
+        Some((HealthState::Healthy, HealthState::Unhealthy))
+    );
+    // Unhealthy rises after 2 consecutive successes
+    assert_eq!(health.record(true, 2, 3), None);
+    assert_eq!(health.record(false, 2, 3), None);
+    assert_eq!(health.record(true, 2, 3), None);
+    assert_eq!(
+        health.record(true, 2, 3),
+        Some((HealthState::Unhealthy, HealthState::Healthy))
+    );
+    // Draining is not changed by the checks
+    health.set(HealthState::Draining);
+    assert_eq!(health.record(false, 2, 3), None);
+    assert_eq!(health.record(true, 2, 3), None);
+    assert_eq!(health.state(), HealthState::Draining);
+}
+
+#[test]
+fn backend_health_check_thresholds_test() {
+    let mut backend = Backend::new(String::from(":5000"), None);
+    backend.set_health_check(HealthCheck {
+        rise: 1,
+        fall: 1,
+        ..HealthCheck::default()
+    });
+    backend.set_online();
+    assert!(backend.is_available());
+    assert!(backend.record_health_check(false).is_some());
+    assert!(!backend.is_available());
+    assert!(backend.record_health_check(true).is_some());
+    assert!(backend.is_available());
+    backend.drain();
+    assert_eq!(backend.health_state(), HealthState::Draining);
+    assert!(!backend.is_available());
+}
+
+#[tokio::test]
+async fn health_probes_concurrent_test() {
+    let healthy = backend("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let blackhole = blackhole().await;
+    let health_check = HealthCheck {
+        timeout: 300,
+        ..HealthCheck::http("/health")
+    };
+    let backends = vec![
+        Backend::new(blackhole, None),
+        Backend::new(healthy.clone(), None),
+        Backend::new(healthy, None),
+    ];
+    let mut pool = BackendPool::new(Box::new(RoundRobinBalancing::new()));
+    for mut backend in backends {
+        backend.set_health_check(health_check.clone());
+        pool.push(backend);
+    }
+    let pool = Arc::new(pool);
+    spawn_probes(
+        pool.clone(),
+        Duration::from_millis(50),
+        HeadLimits::default(),
+    );
+    // The healthy backends are checked without waiting for the blackholed one to time out
+    delay_for(Duration::from_millis(150)).await;
+    assert_eq!(pool[0].health_state(), HealthState::Unknown);
+    assert_eq!(pool[1].health_state(), HealthState::Healthy);
+    assert_eq!(pool[2].health_state(), HealthState::Healthy);
+    assert_eq!(pool.next_backend(), Ok(1));
+    delay_for(Duration::from_millis(300)).await;
+    assert_eq!(pool[0].health_state(), HealthState::Unhealthy);
+}
+
+#[test]
+fn outlier_detection_test() {
+    let config = OutlierDetection {
+        consecutive_failures: 3,
+        base_ejection_time: 1000,
+        max_ejection_time: 3000,
+        ..OutlierDetection::default()
+    };
+    let outlier = Outlier::new();
+    assert!(!outlier.record(false, &config));
+    assert!(!outlier.record(false, &config));
+    // A success resets the streak of failures
+    assert!(!outlier.record(true, &config));
+    assert!(!outlier.record(false, &config));
+    assert!(!outlier.record(false, &config));
+    assert!(outlier.record(false, &config));
+    // Every ejection lasts twice the previous one, up to the max ejection time
+    assert_eq!(outlier.eject(&config), Duration::from_millis(1000));
+    assert!(outlier.is_ejected());
+    assert!(!outlier.record(false, &config));
+    assert_eq!(outlier.eject(&config), Duration::from_millis(2000));
+    assert_eq!(outlier.eject(&config), Duration::from_millis(3000));
+    assert_eq!(outlier.eject(&config), Duration::from_millis(3000));
+}
+
+#[test]
+fn outlier_failure_percentage_test() {
+    let config = OutlierDetection {
+        consecutive_failures: 0,
+        failure_percentage: 50,
+        request_volume: 10,
+        ..OutlierDetection::default()
+    };
+    let outlier = Outlier::new();
+    // 3 failures out of 10 requests
+    for i in 0..9 {
+        assert!(!outlier.record(i % 2 == 0 || i == 1, &config));
+    }
+    assert!(!outlier.record(true, &config));
+    // 5 failures out of the next 10 requests
+    for i in 0..9 {
+        assert!(!outlier.record(i % 2 == 0, &config));
+    }
+    assert!(outlier.record(false, &config));
+}
+
+#[tokio::test]
+async fn outlier_ejection_test() {
+    let mut pool = BackendPool::new(Box::new(RoundRobinBalancing::new()));
+    for port in 0..4 {
+        let backend = Backend::new(format!("127.0.0.1:{}", 5000 + port), None);
+        backend.set_online();
+        pool.push(backend);
+    }
+    pool.set_outlier_detection(OutlierDetection {
+        consecutive_failures: 2,
+        base_ejection_time: 100,
+        max_ejection_percent: 50,
+        ..OutlierDetection::default()
+    });
+    assert_eq!(pool.record_outcome(0, false), None);
+    assert_eq!(
+        pool.record_outcome(0, false),
+        Some(Duration::from_millis(100))
+    );
+    assert!(pool[0].is_ejected());
+    assert!(!pool[0].is_available());
+    assert_eq!(pool.next_backend(), Ok(1));
+    pool.record_outcome(1, false);
+    assert!(pool.record_outcome(1, false).is_some());
+    // No more than half of the backends are ejected
+    pool.record_outcome(2, false);
+    assert_eq!(pool.record_outcome(2, false), None);
+    assert!(pool[2].is_available());
+    // Ejected backends come back once the ejection is over
+    delay_for(Duration::from_millis(150)).await;
+    assert!(pool[0].is_available());
+    assert!(pool[1].is_available());
+}
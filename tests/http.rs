@@ -9,13 +9,13 @@ fn http_parse_message_test() {
         Some(&http::HttpMethod::Get("/hello".to_string()))
     );
     assert_eq!(message.http_version(), Some(&http::HttpVersion::V11));
-    assert_eq!(message.headers.contains_key("Host"), true);
+    assert!(message.headers.contains_key("Host"));
     assert_eq!(message.route(), Some(&"/hello".to_string()));
 }
 
 #[test]
 fn http_request_to_string_test() {
-    let request_str = "GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n\r\n";
+    let request_str = "GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n";
     let message = http::HttpMessage {
         header: http::HttpHeader::Method(
             http::HttpVersion::V11,
@@ -37,3 +37,465 @@ fn http_parse_response_test() {
     let message = http::parse_message(request_bytes).unwrap();
     assert_eq!(message.status_code(), Some(http::StatusCode::new(200)));
 }
+
+#[test]
+fn http_body_length_test() {
+    let message =
+        http::parse_message(b"POST /upload HTTP/1.1\r\nContent-Length: 42\r\n\r\n").unwrap();
+    assert_eq!(message.body_length(), Ok(http::BodyLength::Fixed(42)));
+    let message =
+        http::parse_message(b"HTTP/1.1 200 OK\r\ntransfer-encoding: gzip, chunked\r\n\r\n")
+            .unwrap();
+    assert_eq!(message.body_length(), Ok(http::BodyLength::Chunked));
+    let message = http::parse_message(b"HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n").unwrap();
+    assert_eq!(message.body_length(), Ok(http::BodyLength::UntilClose));
+    let message =
+        http::parse_message(b"HTTP/1.1 304 Not Modified\r\nServer: nginx\r\n\r\n").unwrap();
+    assert_eq!(message.body_length(), Ok(http::BodyLength::Empty));
+}
+
+#[test]
+fn http_body_length_framing_test() {
+    let length = |head: &str| http::parse_message(head.as_bytes()).unwrap().body_length();
+    let invalid = Err(http::HttpError::Invalid);
+    // Content-Length must be made of digits only
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 1 0\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("HTTP/1.1 200 OK\r\nContent-Length: 0x10\r\n\r\n"),
+        invalid
+    );
+    // Repeated values must all be the same
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 0\r\nContent-Length: 5\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\n"),
+        Ok(http::BodyLength::Fixed(5))
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n"),
+        Ok(http::BodyLength::Fixed(5))
+    );
+    // Requests must end their transfer codings with chunked, responses are read until close
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nContent-Length: 5\r\n\r\n"),
+        invalid
+    );
+    assert_eq!(
+        length("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\nContent-Length: 5\r\n\r\n"),
+        Ok(http::BodyLength::UntilClose)
+    );
+    // Transfer-Encoding takes precedence over Content-Length
+    assert_eq!(
+        length("POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n"),
+        Ok(http::BodyLength::Chunked)
+    );
+    // Some statuses never carry a body
+    assert_eq!(
+        length("HTTP/1.1 204 No Content\r\nContent-Length: 5\r\n\r\n"),
+        Ok(http::BodyLength::Empty)
+    );
+    assert_eq!(
+        length("HTTP/1.1 100 Continue\r\nTransfer-Encoding: chunked\r\n\r\n"),
+        Ok(http::BodyLength::Empty)
+    );
+}
+
+#[tokio::test]
+async fn http_read_message_test() {
+    let limits = http::HeadLimits::default();
+    let mut stream: &[u8] = b"\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\nbody";
+    let (message, head) = http::read_message(&mut stream, &limits)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(message.route(), Some(&"/".to_string()));
+    assert_eq!(head, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec());
+    assert_eq!(stream, b"body");
+    let mut stream: &[u8] = b"";
+    assert!(http::read_message(&mut stream, &limits)
+        .await
+        .unwrap()
+        .is_none());
+    let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: loc";
+    assert!(http::read_message(&mut stream, &limits).await.is_err());
+    let mut stream: &[u8] = b"GET / HTTP/2.0\r\nHost: localhost\r\n\r\n";
+    let error = http::read_message(&mut stream, &limits)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        http::HttpError::from_io(&error),
+        Some(&http::HttpError::UnsupportedVersion)
+    );
+}
+
+#[test]
+fn http_parse_head_incomplete_test() {
+    let limits = http::HeadLimits::default();
+    let request = b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\nbody";
+    for i in 0..request.len() - 5 {
+        assert!(matches!(
+            http::parse_head(&request[..i], &limits),
+            Err(http::HttpError::Incomplete)
+        ));
+    }
+    let (message, len) = http::parse_head(request, &limits).unwrap();
+    assert_eq!(len, request.len() - 4);
+    assert_eq!(message.header("Host"), Some("localhost"));
+    assert_eq!(message.body, None);
+}
+
+#[test]
+fn http_parse_head_invalid_test() {
+    let limits = http::HeadLimits::default();
+    let invalid: [&[u8]; 10] = [
+        b"GET /hello\r\n\r\n",
+        b"GET  HTTP/1.1\r\n\r\n",
+        b"F(O) /hello HTTP/1.1\r\n\r\n",
+        b"GET /hello HTTP/1.1\r\nHost\r\n\r\n",
+        b"GET /hello HTTP/1.1\r\nHost : localhost\r\n\r\n",
+        b"GET /hello HTTP/1.1\r\nHost: localhost\r\n folded\r\n\r\n",
+        b"GET /hello HTTP/1.1\r\nX-Bad: a\rb\r\n\r\n",
+        b"GET /hello HTTP/1.1\r\nX-Bad: a\x00b\r\n\r\n",
+        b"GET /hello HTTP/1.1\r\nX-Bad: a\x7fb\r\n\r\n",
+        b"garbage\r\n\r\n",
+    ];
+    for buffer in invalid.iter() {
+        assert!(matches!(
+            http::parse_head(buffer, &limits),
+            Err(http::HttpError::Invalid)
+        ));
+    }
+    assert!(matches!(
+        http::parse_head(b"GET / HTTP/3\r\n\r\n", &limits),
+        Err(http::HttpError::UnsupportedVersion)
+    ));
+    assert!(matches!(
+        http::parse_head(b"HTTP/1.1 OK\r\n\r\n", &limits),
+        Err(http::HttpError::InvalidStatusCode)
+    ));
+}
+
+#[test]
+fn http_parse_head_limits_test() {
+    let limits = http::HeadLimits {
+        max_size: 64,
+        max_headers: 2,
+    };
+    let request = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+    assert!(matches!(
+        http::parse_head(request, &limits),
+        Err(http::HttpError::TooManyHeaders)
+    ));
+    let request = format!("GET /{} HTTP/1.1\r\n", "a".repeat(64));
+    assert!(matches!(
+        http::parse_head(request.as_bytes(), &limits),
+        Err(http::HttpError::HeadTooLarge)
+    ));
+    // Empty lines preceding the start line count in the size of the head
+    let request = "\r\n".repeat(33);
+    assert!(matches!(
+        http::parse_head(request.as_bytes(), &limits),
+        Err(http::HttpError::HeadTooLarge)
+    ));
+    let request = format!("{}GET / HTTP/1.1\r\n\r\n", "\n".repeat(60));
+    assert!(matches!(
+        http::parse_head(request.as_bytes(), &limits),
+        Err(http::HttpError::HeadTooLarge)
+    ));
+    // Tabs are allowed in values
+    let (message, _) = http::parse_head(b"GET / HTTP/1.1\r\nA: 1\t2\r\n\r\n", &limits).unwrap();
+    assert_eq!(message.header("A"), Some("1\t2"));
+}
+
+#[tokio::test]
+async fn http_copy_fixed_body_test() {
+    let payload: Vec<u8> = (0..10000).map(|i| (i % 256) as u8).collect();
+    let mut input = payload.clone();
+    input.extend_from_slice(b"next request");
+    let mut stream: &[u8] = &input;
+    let mut output = Vec::new();
+    let copied = http::copy_body(&mut stream, &mut output, http::BodyLength::Fixed(10000))
+        .await
+        .unwrap();
+    assert_eq!(copied, 10000);
+    assert_eq!(output, payload);
+    assert_eq!(stream, b"next request");
+    let mut stream: &[u8] = b"short";
+    let result = http::copy_body(&mut stream, &mut Vec::new(), http::BodyLength::Fixed(10)).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn http_copy_chunked_body_test() {
+    let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+    let mut input = body.to_vec();
+    input.extend_from_slice(b"HTTP/1.1 200 OK");
+    let mut stream: &[u8] = &input;
+    let mut output = Vec::new();
+    let copied = http::copy_body(&mut stream, &mut output, http::BodyLength::Chunked)
+        .await
+        .unwrap();
+    assert_eq!(copied, body.len() as u64);
+    assert_eq!(output, body.to_vec());
+    assert_eq!(stream, b"HTTP/1.1 200 OK");
+    let mut stream: &[u8] = b"zz\r\nWiki\r\n0\r\n\r\n";
+    let result = http::copy_body(&mut stream, &mut Vec::new(), http::BodyLength::Chunked).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn http_copy_body_limited_test() {
+    // The limit applies to the chunk data, not to the framing
+    let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+    let mut stream: &[u8] = body;
+    let mut output = Vec::new();
+    let copied = http::copy_body_limited(&mut stream, &mut output, http::BodyLength::Chunked, 9)
+        .await
+        .unwrap();
+    assert_eq!(copied, body.len() as u64);
+    let too_large =
+        |e: std::io::Error| http::HttpError::from_io(&e) == Some(&http::HttpError::BodyTooLarge);
+    let mut stream: &[u8] = body;
+    let result =
+        http::copy_body_limited(&mut stream, &mut Vec::new(), http::BodyLength::Chunked, 8).await;
+    assert!(too_large(result.unwrap_err()));
+    let mut stream: &[u8] = b"0123456789";
+    let result =
+        http::copy_body_limited(&mut stream, &mut Vec::new(), http::BodyLength::Fixed(10), 8).await;
+    assert!(too_large(result.unwrap_err()));
+    let mut stream: &[u8] = b"0123456789";
+    let result = http::copy_body_limited(
+        &mut stream,
+        &mut Vec::new(),
+        http::BodyLength::UntilClose,
+        8,
+    )
+    .await;
+    assert!(too_large(result.unwrap_err()));
+    let mut stream: &[u8] = b"0123456789";
+    let result = http::copy_body_limited(
+        &mut stream,
+        &mut Vec::new(),
+        http::BodyLength::Fixed(10),
+        10,
+    )
+    .await;
+    assert_eq!(result.unwrap(), 10);
+}
+
+#[test]
+fn http_keep_alive_test() {
+    let message = http::parse_message(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(message.keep_alive());
+    let message = http::parse_message(b"GET / HTTP/1.1\r\nconnection: Close\r\n\r\n").unwrap();
+    assert!(!message.keep_alive());
+    let message = http::parse_message(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n").unwrap();
+    assert!(!message.keep_alive());
+    let message = http::parse_message(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+    assert!(message.keep_alive());
+}
+
+#[test]
+fn http_parse_methods_test() {
+    let methods = [
+        ("GET", http::HttpMethod::Get("/x".to_string())),
+        ("HEAD", http::HttpMethod::Head("/x".to_string())),
+        ("POST", http::HttpMethod::Post("/x".to_string())),
+        ("PUT", http::HttpMethod::Put("/x".to_string())),
+        ("DELETE", http::HttpMethod::Delete("/x".to_string())),
+        ("CONNECT", http::HttpMethod::Connect("/x".to_string())),
+        ("OPTIONS", http::HttpMethod::Options("/x".to_string())),
+        ("TRACE", http::HttpMethod::Trace("/x".to_string())),
+        ("PATCH", http::HttpMethod::Patch("/x".to_string())),
+        (
+            "PROPFIND",
+            http::HttpMethod::Extension("PROPFIND".to_string(), "/x".to_string()),
+        ),
+    ];
+    for (name, method) in methods.iter() {
+        let request = format!("{} /x HTTP/1.1\r\nHost: localhost\r\n\r\n", name);
+        let message = http::parse_message(request.as_bytes()).unwrap();
+        assert_eq!(message.method(), Some(method));
+        assert_eq!(message.route(), Some(&"/x".to_string()));
+        assert_eq!(method.name(), *name);
+        assert_eq!(
+            format!("{}", message.header),
+            format!("{} /x HTTP/1.1", name)
+        );
+    }
+}
+
+#[test]
+fn http_headers_test() {
+    let response =
+        b"HTTP/1.1 200 OK\r\nset-cookie: a=1\r\nContent-Type:text/html\r\nSet-Cookie: b=2\r\n\r\n";
+    let mut message = http::parse_message(response).unwrap();
+    assert_eq!(message.headers.len(), 3);
+    assert_eq!(message.headers.get("SET-COOKIE"), Some("a=1"));
+    assert_eq!(
+        message.headers.get_all("Set-Cookie").collect::<Vec<_>>(),
+        vec!["a=1", "b=2"]
+    );
+    assert_eq!(message.header("content-type"), Some("text/html"));
+    message.headers.insert("Set-Cookie", "c=3");
+    message.headers.append("X-Forwarded-For", "127.0.0.1");
+    assert_eq!(
+        message.headers.iter().collect::<Vec<_>>(),
+        vec![
+            ("Set-Cookie", "c=3"),
+            ("Content-Type", "text/html"),
+            ("X-Forwarded-For", "127.0.0.1")
+        ]
+    );
+    assert!(message.headers.remove("x-forwarded-for"));
+    assert!(!message.headers.remove("x-forwarded-for"));
+    assert_eq!(
+        format!("{}", message),
+        "HTTP/1.1 200 OK\r\nSet-Cookie: c=3\r\nContent-Type: text/html\r\n\r\n"
+    );
+}
+
+#[test]
+fn http_headers_tokens_test() {
+    let request =
+        b"GET / HTTP/1.1\r\nConnection: Upgrade\r\nconnection: close\r\nTransfer-Encoding: gzip\r\ntransfer-encoding: chunked\r\n\r\n";
+    let message = http::parse_message(request).unwrap();
+    assert!(message.headers.has_token("Connection", "upgrade"));
+    assert!(!message.keep_alive());
+    assert!(message.is_chunked());
+}
+
+#[test]
+fn http_binary_body_test() {
+    let mut response =
+        b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 8\r\n\r\n".to_vec();
+    let body = [0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe, 0x00];
+    response.extend_from_slice(&body);
+    let message = http::parse_message(&response).unwrap();
+    assert_eq!(message.body, Some(body.to_vec()));
+    assert_eq!(message.to_bytes(), response);
+}
+
+#[test]
+fn http_obs_text_test() {
+    // Header values in Latin-1 or in UTF-8 are serialised back byte-for-byte
+    let response =
+        b"HTTP/1.1 200 OK\r\nContent-Disposition: attachment; filename=\"caf\xe9 \xc3\xa9.txt\"\r\n\r\n";
+    let (mut message, _) = http::parse_head(response, &http::HeadLimits::default()).unwrap();
+    assert_eq!(
+        message.header("Content-Disposition"),
+        Some("attachment; filename=\"caf\u{e9} \u{c3}\u{a9}.txt\"")
+    );
+    assert_eq!(message.head_bytes(), response.to_vec());
+    // Other characters added to the head are encoded in UTF-8
+    message.headers.insert("X-Name", "\u{2713}");
+    assert!(message
+        .head_bytes()
+        .ends_with(b"X-Name: \xe2\x9c\x93\r\n\r\n"));
+}
+
+#[test]
+fn http_chunked_decoder_test() {
+    let body =
+        b"4;ext=1\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\nExpires: never\r\n\r\n";
+    // Feed the decoder one byte at a time, lines split across buffers must be handled
+    let mut decoder = http::ChunkedDecoder::new();
+    let mut data = Vec::new();
+    for i in 0..body.len() {
+        let (n, chunk) = decoder.decode(&body[i..i + 1]).unwrap();
+        assert_eq!(n, 1);
+        data.extend_from_slice(chunk);
+    }
+    assert!(decoder.is_done());
+    assert_eq!(data, b"Wikipedia in\r\n\r\nchunks.".to_vec());
+    assert_eq!(decoder.trailers().get("expires"), Some("never"));
+    assert_eq!(decoder.decode(b"extra").unwrap().0, 0);
+    // Whole buffer at once
+    let mut decoder = http::ChunkedDecoder::new();
+    let mut consumed = 0;
+    while !decoder.is_done() {
+        consumed += decoder.decode(&body[consumed..]).unwrap().0;
+    }
+    assert_eq!(consumed, body.len());
+    let mut decoder = http::ChunkedDecoder::new();
+    let error = decoder
+        .decode(b"4\r\nWikiXX\r\n")
+        .and_then(|_| decoder.decode(b"Wiki"));
+    assert!(error.is_ok());
+    assert_eq!(decoder.decode(b"XX\r\n"), Err(http::HttpError::Invalid));
+}
+
+#[test]
+fn http_chunked_encode_test() {
+    assert_eq!(
+        http::encode_chunk(b"Wikipedia"),
+        b"9\r\nWikipedia\r\n".to_vec()
+    );
+    let trailers: http::Headers = [("Expires".to_string(), "never".to_string())]
+        .iter()
+        .cloned()
+        .collect();
+    assert_eq!(
+        http::encode_last_chunk(&trailers),
+        b"0\r\nExpires: never\r\n\r\n".to_vec()
+    );
+    assert_eq!(
+        http::encode_last_chunk(&http::Headers::new()),
+        b"0\r\n\r\n".to_vec()
+    );
+}
+
+#[tokio::test]
+async fn http_copy_chunk_encoded_test() {
+    let payload: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+    let mut stream: &[u8] = &payload;
+    let mut encoded = Vec::new();
+    let written = http::copy_chunk_encoded(&mut stream, &mut encoded)
+        .await
+        .unwrap();
+    assert_eq!(written, encoded.len() as u64);
+    let mut decoder = http::ChunkedDecoder::new();
+    let mut decoded = Vec::new();
+    let mut consumed = 0;
+    while !decoder.is_done() {
+        let (n, data) = decoder.decode(&encoded[consumed..]).unwrap();
+        decoded.extend_from_slice(data);
+        consumed += n;
+    }
+    assert_eq!(consumed, encoded.len());
+    assert_eq!(decoded, payload);
+}
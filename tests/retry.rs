@@ -0,0 +1,108 @@
+mod common;
+
+use common::{backend, send};
+use rlb::backend::{Backend, BackendPool};
+use rlb::balancing::RoundRobinBalancing;
+use rlb::health::{OutlierDetection, StatusRange};
+use rlb::http::{HttpMessage, HttpMethod, StatusCode};
+use rlb::retry::{RetryBudget, RetryPolicy};
+use rlb::routing::Router;
+use rlb::server::{self, Settings};
+use tokio::net::TcpListener;
+
+#[test]
+fn retry_policy_test() {
+    let policy = RetryPolicy::default();
+    let request = |method: &str| {
+        let method = HttpMethod::from_parts(method, "/".to_string()).unwrap();
+        HttpMessage::new(method, Default::default())
+    };
+    assert!(policy.allows(&request("GET")));
+    assert!(policy.allows(&request("PUT")));
+    assert!(!policy.allows(&request("POST")));
+    assert!(!policy.allows(&request("PATCH")));
+    assert!(policy.retries_status(StatusCode::new(503)));
+    assert!(!policy.retries_status(StatusCode::new(500)));
+    let disabled = RetryPolicy {
+        max_retries: 0,
+        ..RetryPolicy::default()
+    };
+    assert!(!disabled.allows(&request("GET")));
+    let config: RetryPolicy =
+        serde_yaml::from_str("max_retries: 2\nstatuses: [\"5xx\", 429]\nper_try_timeout: 500\n")
+            .unwrap();
+    assert_eq!(config.max_retries, 2);
+    assert_eq!(
+        config.statuses,
+        vec![StatusRange::new(500, 599), StatusRange::new(429, 429)]
+    );
+    assert_eq!(config.per_try_timeout, Some(500));
+}
+
+#[test]
+fn retry_budget_test() {
+    let budget = RetryBudget::new(&RetryPolicy {
+        budget_percent: 50,
+        min_retries_per_second: 2,
+        ..RetryPolicy::default()
+    });
+    // The min retries are allowed without any request
+    assert!(budget.try_retry());
+    assert!(budget.try_retry());
+    assert!(!budget.try_retry());
+    for _ in 0..10 {
+        budget.record_request();
+    }
+    // Half of the requests can be retried
+    assert!(budget.try_retry());
+    assert!(budget.try_retry());
+    assert!(budget.try_retry());
+    assert!(!budget.try_retry());
+}
+
+#[tokio::test]
+async fn retry_on_another_backend_test() {
+    let unavailable =
+        backend("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+    let available = backend("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let backends = vec![
+        Backend::new(unavailable, None),
+        Backend::new(available, None),
+    ];
+    for backend in backends.iter() {
+        backend.set_online();
+    }
+    let mut pool = BackendPool::from_backends_list(backends, Box::new(RoundRobinBalancing::new()));
+    // Keep the failing backend in the pool
+    pool.set_outlier_detection(OutlierDetection {
+        consecutive_failures: 0,
+        ..OutlierDetection::default()
+    });
+    tokio::spawn(async move {
+        server::run(
+            listener,
+            Router::with_default_pool(pool),
+            Settings::default(),
+        )
+        .await
+    });
+    for _ in 0..4 {
+        let response = send(&addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+    for _ in 0..2 {
+        let put = "PUT / HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody";
+        let response = send(&addr, put).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+    // Requests with a method not idempotent are not retried
+    let mut statuses = Vec::new();
+    for _ in 0..2 {
+        let response = send(&addr, "POST / HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        statuses.push(response[9..12].to_string());
+    }
+    statuses.sort();
+    assert_eq!(statuses, vec!["200", "503"]);
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use common::{backend, send};
+use rlb::backend::{Backend, BackendPool};
+use rlb::balancing::{BalancingAlgorithm, RoundRobinBalancing};
+use rlb::http::{parse_message, HttpMessage};
+use rlb::routing::{Route, RouteConfig, Router, DEFAULT_POOL};
+use rlb::server::{self, Settings};
+use rlb::Config;
+use tokio::net::TcpListener;
+
+const CONF: &str = r#"
+listen_on: "127.0.0.1:6767"
+probe_interval: 5000
+backends:
+    - "127.0.0.1:7000"
+pools:
+    api:
+        backends:
+            - "127.0.0.1:7001"
+            - "127.0.0.1:7002"
+        balancing: least-connections
+    static:
+        backends:
+            - "127.0.0.1:7003"
+        health_check:
+            path: /ping
+routes:
+    - host: "*.example.com"
+      path_prefix: /api/
+      pool: api
+    - path_regex: "\\.(css|js|png)$"
+      methods: [GET, HEAD]
+      pool: static
+"#;
+
+fn request(raw: &str) -> HttpMessage {
+    parse_message(raw.as_bytes()).unwrap()
+}
+
+fn pool(addr: &str) -> BackendPool {
+    BackendPool::from_backends_list(
+        vec![Backend::new(addr.to_string(), None)],
+        Box::new(RoundRobinBalancing::new()),
+    )
+}
+
+/// Return the address of the first backend of the pool `request` is routed to
+fn routed(router: &Router, raw: &str) -> Option<String> {
+    router.route(&request(raw)).map(|p| p[0].addr.clone())
+}
+
+#[test]
+fn route_matches_test() {
+    let config = RouteConfig {
+        host: Some("api.example.com".to_string()),
+        path_prefix: Some("/v1/".to_string()),
+        methods: vec!["POST".to_string()],
+        headers: vec![("X-Tenant".to_string(), "acme".to_string())]
+            .into_iter()
+            .collect(),
+        ..RouteConfig::default()
+    };
+    let route = Route::from_config(&config, 0).unwrap();
+    assert!(route.matches(&request(
+        "POST /v1/users?page=2 HTTP/1.1\r\nhost: API.example.com:8080\r\nx-tenant: acme\r\n\r\n"
+    )));
+    assert!(!route.matches(&request(
+        "GET /v1/users HTTP/1.1\r\nHost: api.example.com\r\nX-Tenant: acme\r\n\r\n"
+    )));
+    assert!(!route.matches(&request(
+        "POST /v2/users HTTP/1.1\r\nHost: api.example.com\r\nX-Tenant: acme\r\n\r\n"
+    )));
+    assert!(!route.matches(&request(
+        "POST /v1/users HTTP/1.1\r\nHost: www.example.com\r\nX-Tenant: acme\r\n\r\n"
+    )));
+    assert!(!route.matches(&request(
+        "POST /v1/users HTTP/1.1\r\nHost: api.example.com\r\nX-Tenant: other\r\n\r\n"
+    )));
+    assert!(!route.matches(&request(
+        "POST /v1/users HTTP/1.1\r\nX-Tenant: acme\r\n\r\n"
+    )));
+}
+
+#[test]
+fn route_wildcard_host_and_regex_test() {
+    let config = RouteConfig {
+        host: Some("*.example.com".to_string()),
+        path_regex: Some("^/assets/[a-z]+\\.css$".to_string()),
+        ..RouteConfig::default()
+    };
+    let route = Route::from_config(&config, 0).unwrap();
+    assert!(route.matches(&request(
+        "GET /assets/main.css?v=3 HTTP/1.1\r\nHost: cdn.example.com\r\n\r\n"
+    )));
+    assert!(route.matches(&request(
+        "HEAD /assets/main.css HTTP/1.1\r\nHost: a.b.example.com\r\n\r\n"
+    )));
+    assert!(!route.matches(&request(
+        "GET /assets/main.css HTTP/1.1\r\nHost: example.com\r\n\r\n"
+    )));
+    assert!(!route.matches(&request(
+        "GET /assets/main.css HTTP/1.1\r\nHost: badexample.com\r\n\r\n"
+    )));
+    assert!(!route.matches(&request(
+        "GET /assets/main.js HTTP/1.1\r\nHost: cdn.example.com\r\n\r\n"
+    )));
+    let invalid = RouteConfig {
+        path_regex: Some("(".to_string()),
+        ..RouteConfig::default()
+    };
+    assert!(Route::from_config(&invalid, 0).is_err());
+}
+
+#[test]
+fn router_from_config_test() {
+    let config: Config = serde_yaml::from_str(CONF).unwrap();
+    assert!(config.validate().is_ok());
+    assert_eq!(config.pools().len(), 2);
+    assert_eq!(
+        config.pools()["api"].balancing,
+        BalancingAlgorithm::least_connections()
+    );
+    assert_eq!(config.routes().len(), 2);
+    let router = Router::from_config(&config).unwrap();
+    let names: Vec<&str> = router.pools().map(|(name, _)| name).collect();
+    assert_eq!(names, vec![DEFAULT_POOL, "api", "static"]);
+    assert_eq!(router.pool("api").unwrap().len(), 2);
+    assert_eq!(
+        routed(
+            &router,
+            "GET /api/users HTTP/1.1\r\nHost: www.example.com\r\n\r\n"
+        ),
+        Some("127.0.0.1:7001".to_string())
+    );
+    assert_eq!(
+        routed(
+            &router,
+            "GET /api/users HTTP/1.1\r\nHost: example.org\r\n\r\n"
+        ),
+        Some("127.0.0.1:7000".to_string())
+    );
+    assert_eq!(
+        routed(
+            &router,
+            "GET /img/logo.png HTTP/1.1\r\nHost: example.org\r\n\r\n"
+        ),
+        Some("127.0.0.1:7003".to_string())
+    );
+    assert_eq!(
+        routed(
+            &router,
+            "POST /img/logo.png HTTP/1.1\r\nHost: example.org\r\n\r\n"
+        ),
+        Some("127.0.0.1:7000".to_string())
+    );
+}
+
+#[test]
+fn router_without_default_test() {
+    let conf = CONF.replace("backends:\n    - \"127.0.0.1:7000\"\n", "");
+    let config: Config = serde_yaml::from_str(&conf).unwrap();
+    assert!(config.validate().is_ok());
+    let router = Router::from_config(&config).unwrap();
+    assert!(router.pool(DEFAULT_POOL).is_none());
+    assert_eq!(
+        routed(
+            &router,
+            "GET /api/ HTTP/1.1\r\nHost: api.example.com\r\n\r\n"
+        ),
+        Some("127.0.0.1:7001".to_string())
+    );
+    assert!(routed(&router, "GET / HTTP/1.1\r\nHost: example.org\r\n\r\n").is_none());
+}
+
+#[test]
+fn router_invalid_config_test() {
+    let conf = CONF.replace("pool: static", "pool: admin");
+    let config: Config = serde_yaml::from_str(&conf).unwrap();
+    assert!(Router::from_config(&config).is_err());
+    let conf = CONF.replace("            - \"127.0.0.1:7003\"\n", "            []\n");
+    let config: Config = serde_yaml::from_str(&conf).unwrap();
+    assert!(Router::from_config(&config).is_err());
+    let mut router = Router::with_default_pool(pool("127.0.0.1:7000"));
+    assert!(router
+        .add_pool(DEFAULT_POOL, pool("127.0.0.1:7001"))
+        .is_err());
+    assert!(router.add_pool("admin", pool("127.0.0.1:7001")).is_ok());
+    let route = RouteConfig {
+        path_prefix: Some("/admin".to_string()),
+        pool: "admin".to_string(),
+        ..RouteConfig::default()
+    };
+    assert!(router.add_route(&route).is_ok());
+    assert_eq!(
+        routed(&router, "GET /admin/users HTTP/1.1\r\n\r\n"),
+        Some("127.0.0.1:7001".to_string())
+    );
+    assert_eq!(
+        routed(&router, "GET /users HTTP/1.1\r\n\r\n"),
+        Some("127.0.0.1:7000".to_string())
+    );
+}
+
+#[tokio::test]
+async fn router_forward_test() {
+    let backend_addr = backend("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\napi").await;
+    let api = pool(&backend_addr);
+    api[0].set_online();
+    let mut router = Router::new();
+    router.add_pool("api", api).unwrap();
+    let route = RouteConfig {
+        path_prefix: Some("/api/".to_string()),
+        pool: "api".to_string(),
+        ..RouteConfig::default()
+    };
+    router.add_route(&route).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { server::run(listener, router, Settings::default()).await });
+    let response = send(
+        &addr,
+        "GET /api/users HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("api"), "{}", response);
+    // Requests matching no route without a default pool are answered with a 404
+    let response = send(&addr, "GET /admin HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{}",
+        response
+    );
+}
@@ -0,0 +1,594 @@
+mod common;
+
+use common::{backend, blackhole, send};
+use rlb::backend::{Backend, BackendPool};
+use rlb::balancing::RoundRobinBalancing;
+use rlb::http::{copy_body, parse_head, read_message, HeadLimits, HttpMessage, StatusCode};
+use rlb::routing::Router;
+use rlb::server::{self, ErrorPages, Settings, Timeouts};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+/// Start the load-balancer with the settings in `conf` forwarding to `backends`, all set
+/// online, and return its address
+async fn start(conf: &str, backends: Vec<String>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let settings: Settings =
+        serde_yaml::from_str(&format!("probe_interval: 60000\n{}", conf)).unwrap();
+    let backends: Vec<Backend> = backends
+        .into_iter()
+        .map(|b| Backend::new(b, None))
+        .collect();
+    for backend in backends.iter() {
+        backend.set_online();
+    }
+    let pool = BackendPool::from_backends_list(backends, Box::new(RoundRobinBalancing::new()));
+    tokio::spawn(
+        async move { server::run(listener, Router::with_default_pool(pool), settings).await },
+    );
+    addr
+}
+
+/// Read the next response on `stream`, return its head and its body, framed by
+/// `Content-Length`
+async fn read_response(stream: &mut BufReader<TcpStream>) -> (HttpMessage, String) {
+    let limits = HeadLimits::default();
+    let (response, _) = read_message(stream, &limits).await.unwrap().unwrap();
+    let mut body = vec![0; response.content_length().unwrap().unwrap_or(0) as usize];
+    stream.read_exact(&mut body).await.unwrap();
+    (response, String::from_utf8(body).unwrap())
+}
+
+/// Spawn a backend answering every request with a `100 Continue` followed by a response
+/// carrying the request line, keeping the connections alive. Return its address along with
+/// the number of connections it served requests on.
+async fn continue_backend() -> (String, Arc<AtomicUsize>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut socket = BufReader::new(socket);
+                let limits = HeadLimits::default();
+                let mut served = false;
+                while let Ok(Some((request, _))) = read_message(&mut socket, &limits).await {
+                    if !served {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        served = true;
+                    }
+                    let length = request.body_length().unwrap();
+                    copy_body(&mut socket, &mut io::sink(), length)
+                        .await
+                        .unwrap();
+                    let body = format!("resp-for:{}", request.method().unwrap());
+                    let response = format!(
+                        "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+    (addr, connections)
+}
+
+/// Spawn a backend answering every request with its request line, reading the request bodies
+/// as framed by their headers and listing `Content-Length` in the `Connection` header of the
+/// responses. Return its address.
+async fn echo_backend() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut socket = BufReader::new(socket);
+                let limits = HeadLimits::default();
+                while let Ok(Some((request, _))) = read_message(&mut socket, &limits).await {
+                    let length = request.body_length().unwrap();
+                    copy_body(&mut socket, &mut io::sink(), length)
+                        .await
+                        .unwrap();
+                    let body = format!("resp-for:{}", request.method().unwrap());
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: Content-Length\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn interim_responses_test() {
+    let (backend, connections) = continue_backend().await;
+    let addr = start("", vec![backend]).await;
+    let mut stream = BufReader::new(TcpStream::connect(&addr).await.unwrap());
+    stream
+        .write_all(b"PUT /a HTTP/1.1\r\nContent-Length: 4\r\nExpect: 100-continue\r\n\r\n")
+        .await
+        .unwrap();
+    // The client waits for the `100 Continue` before sending the body
+    let read = time::timeout(Duration::from_secs(2), read_response(&mut stream));
+    let (response, _) = read.await.unwrap();
+    assert_eq!(response.status_code(), Some(StatusCode::CONTINUE));
+    stream.write_all(b"body").await.unwrap();
+    // The interim response of the backend is forwarded, followed by the final one
+    let (response, _) = read_response(&mut stream).await;
+    assert_eq!(response.status_code(), Some(StatusCode::CONTINUE));
+    let (response, body) = read_response(&mut stream).await;
+    assert_eq!(response.status_code(), Some(StatusCode::new(200)));
+    assert_eq!(body, "resp-for:PUT /a");
+    // Another client reuses the upstream connection and gets its own response
+    let response = send(&addr, "GET /b HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"),
+        "{}",
+        response
+    );
+    assert!(response.ends_with("resp-for:GET /b"), "{}", response);
+    // HTTP/1.0 clients only get the final response
+    let response = send(&addr, "GET /c HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("resp-for:GET /c"), "{}", response);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn keep_alive_test() {
+    let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: keep-alive, X-Hop\r\n\
+                    Keep-Alive: timeout=5\r\nX-Hop: 1\r\n\r\nok";
+    let addr = start("keep_alive_timeout: 200\n", vec![backend(response).await]).await;
+    let mut stream = BufReader::new(TcpStream::connect(&addr).await.unwrap());
+    // Requests sent one after the other on the same connection
+    for _ in 0..2 {
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let (response, body) = read_response(&mut stream).await;
+        assert_eq!(response.status_code(), Some(StatusCode::new(200)));
+        assert_eq!(body, "ok");
+        // The hop-by-hop headers of the backend are not forwarded
+        assert_eq!(response.header("Connection"), None);
+        assert_eq!(response.header("Keep-Alive"), None);
+        assert_eq!(response.header("X-Hop"), None);
+    }
+    // Pipelined requests are answered in order, HTTP/1.0 clients are told the connection is
+    // kept alive
+    stream
+        .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+        .await
+        .unwrap();
+    let (response, body) = read_response(&mut stream).await;
+    assert_eq!((response.header("Connection"), body.as_str()), (None, "ok"));
+    let (response, body) = read_response(&mut stream).await;
+    assert_eq!(
+        (response.header("Connection"), body.as_str()),
+        (Some("keep-alive"), "ok")
+    );
+    // The connection is closed once idle for `keep_alive_timeout`
+    let mut rest = Vec::new();
+    let read = time::timeout(Duration::from_secs(2), stream.read_to_end(&mut rest)).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+}
+
+#[tokio::test]
+async fn hop_by_hop_framing_test() {
+    let addr = start("", vec![echo_backend().await]).await;
+    let mut stream = BufReader::new(TcpStream::connect(&addr).await.unwrap());
+    // Listing the framing headers in `Connection` doesn't remove them, the body is never sent
+    // unframed to be read as another request
+    let smuggled = "GET /admin HTTP/1.1\r\n\r\n";
+    let request = format!(
+        "POST / HTTP/1.1\r\nContent-Length: {}\r\nConnection: Content-Length\r\n\r\n{}",
+        smuggled.len(),
+        smuggled
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let (response, body) = read_response(&mut stream).await;
+    assert_eq!(body, "resp-for:POST /");
+    assert_eq!(response.header("Content-Length"), Some("15"));
+    let request = format!(
+        "POST /chunked HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: transfer-encoding\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+        smuggled.len(),
+        smuggled
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let (_, body) = read_response(&mut stream).await;
+    assert_eq!(body, "resp-for:POST /chunked");
+    stream
+        .write_all(b"GET /next HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let (_, body) = read_response(&mut stream).await;
+    assert_eq!(body, "resp-for:GET /next");
+}
+
+#[tokio::test]
+async fn chunk_encode_test() {
+    // Backend marking the end of the body by closing the connection
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = [0; 4096];
+            if socket.read(&mut buf).await.unwrap_or(0) > 0 {
+                let response = "HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\
+                                Content-Length: 2\r\n\r\n\x1f\x08zip";
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        }
+    });
+    let addr = start("", vec![backend]).await;
+    let mut stream = BufReader::new(TcpStream::connect(&addr).await.unwrap());
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let limits = HeadLimits::default();
+    let (response, _) = read_message(&mut stream, &limits).await.unwrap().unwrap();
+    // HTTP/1.1 clients get the body encoded in chunks on top of the codings of the backend
+    assert_eq!(response.header("Transfer-Encoding"), Some("gzip, chunked"));
+    assert_eq!(response.header("Content-Length"), None);
+    let mut body = Vec::new();
+    copy_body(&mut stream, &mut body, response.body_length().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(body, b"5\r\n\x1f\x08zip\r\n0\r\n\r\n".to_vec());
+    // HTTP/1.0 clients read the body until the connection is closed
+    let response = send(&addr, "GET / HTTP/1.0\r\n\r\n").await;
+    assert!(
+        response.contains("Transfer-Encoding: gzip\r\n"),
+        "{}",
+        response
+    );
+    assert!(!response.contains("Content-Length"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n\x1f\x08zip"), "{}", response);
+}
+
+#[tokio::test]
+async fn obs_text_test() {
+    // Backend sending back the bytes of the `X-Name` request header as the file name
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = [0; 4096];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let name = buf[..n]
+                .split(|&b| b == b'\n')
+                .find_map(|line| line.strip_prefix(b"X-Name: "));
+            if let Some(name) = name {
+                let mut response =
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nContent-Disposition: filename="
+                        .to_vec();
+                response.extend_from_slice(name);
+                response.extend_from_slice(b"\n\r\n");
+                socket.write_all(&response).await.unwrap();
+            }
+        }
+    });
+    let addr = start("", vec![backend]).await;
+    let mut stream = BufReader::new(TcpStream::connect(&addr).await.unwrap());
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nX-Name: caf\xe9 \xc3\xa9.txt\r\n\r\n")
+        .await
+        .unwrap();
+    let (response, _) = read_response(&mut stream).await;
+    let name = b"filename=caf\xe9 \xc3\xa9.txt\r\n";
+    let head = response.head_bytes();
+    assert!(head.windows(name.len()).any(|w| w == name), "{}", response);
+}
+
+#[tokio::test]
+async fn stale_response_test() {
+    // Backend sending an extra response after each one
+    let response = "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nx\
+                    HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nSTALE";
+    let addr = start("", vec![backend(response).await]).await;
+    for _ in 0..2 {
+        let response = send(&addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert!(response.ends_with("\r\n\r\nx"), "{}", response);
+    }
+}
+
+#[tokio::test]
+async fn resend_idempotent_test() {
+    // Backend closing every connection after answering a request, although it's kept alive
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = [0; 4096];
+            if socket.read(&mut buf).await.unwrap_or(0) > 0 {
+                let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        }
+    });
+    let addr = start("", vec![backend]).await;
+    let get = "GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let response = send(&addr, get).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    time::delay_for(Duration::from_millis(50)).await;
+    // Idempotent requests are sent again on a new connection
+    let response = send(&addr, get).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    time::delay_for(Duration::from_millis(50)).await;
+    // Other requests may have been processed by the backend already
+    let post = "POST / HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let response = send(&addr, post).await;
+    assert!(
+        response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn error_pages_test() {
+    let pages: ErrorPages = serde_yaml::from_str(
+        "content_type: text/html\nretry_after: 30\npages:\n    502: \"<h1>Oops</h1>\"\n",
+    )
+    .unwrap();
+    let response = pages.response(StatusCode::BAD_GATEWAY);
+    assert_eq!(response.status_code(), Some(StatusCode::BAD_GATEWAY));
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(response.content_length(), Ok(Some(13)));
+    assert_eq!(response.header("Retry-After"), None);
+    assert_eq!(response.body, Some(b"<h1>Oops</h1>".to_vec()));
+    let response = pages.response(StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.header("Retry-After"), Some("30"));
+    assert_eq!(response.body, Some(b"503 Service Unavailable\n".to_vec()));
+    // The response can be parsed back
+    let bytes = response.to_bytes();
+    let (parsed, _) = parse_head(&bytes, &HeadLimits::default()).unwrap();
+    assert_eq!(parsed.status_code(), Some(StatusCode::SERVICE_UNAVAILABLE));
+}
+
+#[tokio::test]
+async fn error_responses_test() {
+    // A port nothing listens on
+    let refused = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let addr = start("max_body_size: 16\n", vec![refused]).await;
+    let response = send(&addr, "GET / HTTP/1.1\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+        "{}",
+        response
+    );
+    assert!(response.contains("Connection: close\r\n"));
+    let response = send(&addr, "GET / HTTP/1.1 extra\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{}",
+        response
+    );
+    let header = format!("X-Large: {}\r\n", "a".repeat(20000));
+    let response = send(&addr, &format!("GET / HTTP/1.1\r\n{}\r\n", header)).await;
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    // Requests whose body can't be delimited are rejected, not to forward the body as another
+    // request
+    let response = send(
+        &addr,
+        "POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{}",
+        response
+    );
+    assert_eq!(response.matches("HTTP/1.1").count(), 1);
+    let response = send(&addr, "POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+        "{}",
+        response
+    );
+    let response = send(&addr, "GET / HTTP/2.0\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn error_chunked_body_too_large_test() {
+    let backend = backend("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let addr = start("max_body_size: 16\n", vec![backend]).await;
+    let request = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+    let response = send(
+        &addr,
+        &format!("{}10\r\n{}\r\n0\r\n\r\n", request, "a".repeat(16)),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    // The size of chunked bodies is only known while they're streamed
+    let body = "8\r\naaaaaaaa\r\n8\r\naaaaaaaa\r\n1\r\na\r\n0\r\n\r\n";
+    let response = send(&addr, &format!("{}{}", request, body)).await;
+    assert!(
+        response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+        "{}",
+        response
+    );
+    assert!(response.contains("Connection: close\r\n"));
+}
+
+#[tokio::test]
+async fn error_no_backend_available_test() {
+    let addr = start("", vec![]).await;
+    // The connection is kept alive for the following requests
+    let response = send(
+        &addr,
+        "GET / HTTP/1.1\r\n\r\nHEAD / HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(
+        response
+            .matches("HTTP/1.1 503 Service Unavailable\r\n")
+            .count(),
+        2
+    );
+    assert_eq!(response.matches("Retry-After: 5\r\n").count(), 2);
+    // Only the last response closes the connection, the one to `HEAD` has no body
+    assert_eq!(response.matches("Connection: close").count(), 1);
+    assert!(
+        response.ends_with("Connection: close\r\n\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn error_gateway_timeout_test() {
+    let addr = start(
+        "retry:\n    per_try_timeout: 100\n",
+        vec![blackhole().await],
+    )
+    .await;
+    let response = send(&addr, "GET / HTTP/1.1\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn timeouts_config_test() {
+    let timeouts: Timeouts = serde_yaml::from_str("connect: 250\nfirst_byte: ~\n").unwrap();
+    assert_eq!(timeouts.connect, Some(250));
+    assert_eq!(timeouts.first_byte, None);
+    assert_eq!(timeouts.client_header, Timeouts::default().client_header);
+    assert_eq!(timeouts.request, None);
+    assert_eq!(timeouts.body_idle, Some(60000));
+}
+
+#[tokio::test]
+async fn timeouts_test() {
+    let conf = "timeouts:\n    client_header: 100\n    client_body: 100\n    first_byte: 100\n";
+    let addr = start(conf, vec![blackhole().await]).await;
+    // Incomplete head
+    let response = send(&addr, "GET / HTTP/1.1\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "{}",
+        response
+    );
+    // Incomplete body
+    let response = send(&addr, "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nab").await;
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "{}",
+        response
+    );
+    // No response from the backend
+    let response = send(&addr, "GET / HTTP/1.1\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn timeouts_body_idle_test() {
+    // Backend sending the start of the body of its response, then nothing
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut sockets = Vec::new();
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = [0; 4096];
+            // Health checks only open a connection
+            if socket.read(&mut buf).await.unwrap_or(0) == 0 {
+                continue;
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nab";
+            socket.write_all(response.as_bytes()).await.unwrap();
+            sockets.push(socket);
+        }
+    });
+    let conf = "timeouts:\n    client_body: ~\n    body_idle: 100\n";
+    let addr = start(conf, vec![backend]).await;
+    // The response is cut short by closing the connection
+    let send_response = send(&addr, "GET / HTTP/1.1\r\n\r\n");
+    let response = time::timeout(Duration::from_secs(1), send_response)
+        .await
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nab"), "{}", response);
+    // Request bodies are bounded even without `client_body`
+    let send_request = send(&addr, "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nab");
+    let response = time::timeout(Duration::from_secs(1), send_request)
+        .await
+        .unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn timeouts_client_write_test() {
+    // Backend sending a response too large to be buffered, recording whether it was cut short
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    let aborted = Arc::new(AtomicUsize::new(0));
+    let counter = aborted.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = [0; 4096];
+            if socket.read(&mut buf).await.unwrap_or(0) == 0 {
+                continue;
+            }
+            let body = vec![b'a'; 64 << 20];
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            socket.write_all(head.as_bytes()).await.unwrap();
+            if socket.write_all(&body).await.is_err() {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    });
+    let addr = start("timeouts:\n    body_idle: 100\n", vec![backend]).await;
+    // Client sending a request and never reading the response, the upstream connection is
+    // closed rather than held forever
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    for _ in 0..50 {
+        if aborted.load(Ordering::SeqCst) > 0 {
+            break;
+        }
+        time::delay_for(Duration::from_millis(100)).await;
+    }
+    assert_eq!(aborted.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn timeouts_request_test() {
+    let conf = "timeouts:\n    first_byte: 5000\n    request: 100\n";
+    let addr = start(conf, vec![blackhole().await]).await;
+    let start = std::time::Instant::now();
+    let response = send(&addr, "GET / HTTP/1.1\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+        "{}",
+        response
+    );
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+}